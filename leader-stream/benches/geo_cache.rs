@@ -0,0 +1,72 @@
+//! Compares the geolocation cache read path under `tokio::sync::RwLock`
+//! against `parking_lot::RwLock` with 1000 concurrent readers.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use tokio::runtime::Runtime;
+
+const READERS: usize = 1000;
+const ENTRIES: usize = 256;
+
+type Entry = Option<(f64, f64)>;
+
+fn cache_entries() -> HashMap<String, Entry> {
+    (0..ENTRIES)
+        .map(|index| {
+            let ip = format!("10.0.{}.{}", index / 256, index % 256);
+            (ip, Some((index as f64, -(index as f64))))
+        })
+        .collect()
+}
+
+fn bench_cache_reads(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("tokio runtime");
+    let mut group = c.benchmark_group("geo_cache_reads");
+
+    let tokio_cache = Arc::new(tokio::sync::RwLock::new(cache_entries()));
+    group.bench_function("tokio_rwlock", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let tasks = (0..READERS)
+                    .map(|index| {
+                        let cache = Arc::clone(&tokio_cache);
+                        tokio::spawn(async move {
+                            let ip = format!("10.0.0.{}", index % ENTRIES);
+                            cache.read().await.get(&ip).cloned()
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                for task in tasks {
+                    let _ = task.await;
+                }
+            })
+        })
+    });
+
+    let parking_cache = Arc::new(parking_lot::RwLock::new(cache_entries()));
+    group.bench_function("parking_lot_rwlock", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let tasks = (0..READERS)
+                    .map(|index| {
+                        let cache = Arc::clone(&parking_cache);
+                        tokio::spawn(async move {
+                            let ip = format!("10.0.0.{}", index % ENTRIES);
+                            cache.read().get(&ip).cloned()
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                for task in tasks {
+                    let _ = task.await;
+                }
+            })
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_cache_reads);
+criterion_main!(benches);
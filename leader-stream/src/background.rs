@@ -342,7 +342,7 @@ async fn ensure_leaders_for_range(
     end_slot: u64,
     lookahead: usize,
 ) -> Result<()> {
-    let lookahead = lookahead.max(2).min(DEFAULT_TRACK_LOOKAHEAD);
+    let lookahead = lookahead.clamp(2, DEFAULT_TRACK_LOOKAHEAD);
     let range_start = std::cmp::min(start_slot, end_slot);
     let range_end = std::cmp::max(start_slot, end_slot);
     let cache_end = cache.end_slot();
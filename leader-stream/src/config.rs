@@ -1,13 +1,18 @@
+use std::collections::HashMap;
 use std::env;
+use std::path::PathBuf;
 use std::time::Duration;
 
 use anyhow::Result;
 use tracing::warn;
 
 use crate::constants::{
-    DEFAULT_HEARTBEAT_MS, DEFAULT_LEADER_LOOKAHEAD, DEFAULT_NODE_CACHE_TTL_MS, DEFAULT_PORT,
-    DEFAULT_REQUEST_TIMEOUT_MS, DEFAULT_RPC_URL, DEFAULT_TRACK_LOOKAHEAD, DEFAULT_WS_PING_MS,
+    DEFAULT_HEARTBEAT_MS, DEFAULT_LEADER_LOOKAHEAD, DEFAULT_MAXMIND_CONNECT_TIMEOUT_MS,
+    DEFAULT_MAXMIND_DOWNLOAD_TIMEOUT_MS, DEFAULT_MAXMIND_MAX_DOWNLOAD_BYTES,
+    DEFAULT_NODE_CACHE_TTL_MS, DEFAULT_PORT, DEFAULT_REQUEST_TIMEOUT_MS, DEFAULT_RPC_URL,
+    DEFAULT_TRACK_LOOKAHEAD, DEFAULT_WS_PING_MS,
 };
+use crate::geo::GeoPoint;
 
 #[derive(Clone)]
 pub(crate) struct Config {
@@ -27,6 +32,37 @@ pub(crate) struct Config {
     pub(crate) maxmind_edition_id: String,
     pub(crate) maxmind_db_download_url: Option<String>,
     pub(crate) maxmind_fallback_url: Option<String>,
+    pub(crate) maxmind_download_connect_timeout: Duration,
+    /// Whole-request timeout for database downloads, including the body.
+    pub(crate) maxmind_download_read_timeout: Duration,
+    /// Downloads larger than this are aborted, whether announced via
+    /// `Content-Length` or discovered while streaming.
+    pub(crate) maxmind_max_download_bytes: u64,
+    /// HTTP basic auth for private mirrors at `maxmind_db_download_url` or
+    /// `maxmind_fallback_url`; used only when both are set. Unrelated to the
+    /// MaxMind license key, which is never sent with these credentials.
+    pub(crate) maxmind_download_basic_auth_user: Option<String>,
+    pub(crate) maxmind_download_basic_auth_password: Option<String>,
+    /// Never download the database; a missing `maxmind_db_path` is an error.
+    pub(crate) maxmind_offline: bool,
+    pub(crate) enable_anonymization: bool,
+    pub(crate) maxmind_anon_db_path: Option<String>,
+    /// Only loaded for embedders calling `GeoIpService::lookup_asn`; no HTTP
+    /// handler queries it.
+    pub(crate) maxmind_asn_db_path: Option<String>,
+    /// Only loaded for embedders calling
+    /// `GeoIpService::lookup_registered_domain`; no HTTP handler queries it.
+    pub(crate) maxmind_domain_db_path: Option<String>,
+    pub(crate) maxmind_lookup_timeout_ms: Option<u64>,
+    /// Log each IP missing from the database together with its miss count.
+    pub(crate) trace_not_found: bool,
+    /// Fixed geolocation for specific IPs (e.g. RFC 1918 staging nodes),
+    /// seeded into the GeoIP cache before any real lookup. Database lookups
+    /// and reloads never replace them; see `GeoIpService::with_overrides` for
+    /// the cache operations that do.
+    pub(crate) geo_overrides: HashMap<String, GeoPoint>,
+    /// `city,timezone` CSV consulted when a City record has no time zone.
+    pub(crate) timezone_fallback_csv: Option<PathBuf>,
 }
 
 impl Config {
@@ -103,6 +139,35 @@ impl Config {
             env::var("MAXMIND_EDITION_ID").unwrap_or_else(|_| "GeoLite2-City".to_string());
         let maxmind_db_download_url = read_env_first(&["MAXMIND_DB_DOWNLOAD_URL"]);
         let maxmind_fallback_url = read_env_first(&["MAXMIND_FALLBACK_URL"]);
+        let maxmind_download_connect_timeout = Duration::from_millis(
+            env::var("MAXMIND_DOWNLOAD_CONNECT_TIMEOUT_MS")
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+                .unwrap_or(DEFAULT_MAXMIND_CONNECT_TIMEOUT_MS),
+        );
+        let maxmind_download_read_timeout = Duration::from_millis(
+            env::var("MAXMIND_DOWNLOAD_TIMEOUT_MS")
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+                .unwrap_or(DEFAULT_MAXMIND_DOWNLOAD_TIMEOUT_MS),
+        );
+        let maxmind_max_download_bytes = env::var("MAXMIND_MAX_DOWNLOAD_BYTES")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_MAXMIND_MAX_DOWNLOAD_BYTES);
+        let maxmind_download_basic_auth_user = read_env_first(&["MAXMIND_DOWNLOAD_USER"]);
+        let maxmind_download_basic_auth_password = read_env_first(&["MAXMIND_DOWNLOAD_PASSWORD"]);
+        let maxmind_offline = read_env_flag("MAXMIND_OFFLINE");
+        let enable_anonymization = read_env_flag("GEOIP_ANONYMIZE");
+        let maxmind_anon_db_path = read_env_first(&["MAXMIND_ANON_DB_PATH"]);
+        let maxmind_asn_db_path = read_env_first(&["MAXMIND_ASN_DB_PATH"]);
+        let maxmind_domain_db_path = read_env_first(&["MAXMIND_DOMAIN_DB_PATH"]);
+        let maxmind_lookup_timeout_ms = read_env_first(&["MAXMIND_LOOKUP_TIMEOUT_MS"])
+            .and_then(|value| value.parse::<u64>().ok());
+        let trace_not_found = read_env_flag("GEOIP_TRACE_NOT_FOUND");
+        let geo_overrides = read_geo_overrides();
+        let timezone_fallback_csv =
+            read_env_first(&["GEOIP_TIMEZONE_FALLBACK_CSV"]).map(PathBuf::from);
 
         Ok(Self {
             rpc_url,
@@ -121,6 +186,20 @@ impl Config {
             maxmind_edition_id,
             maxmind_db_download_url,
             maxmind_fallback_url,
+            maxmind_download_connect_timeout,
+            maxmind_download_read_timeout,
+            maxmind_max_download_bytes,
+            maxmind_download_basic_auth_user,
+            maxmind_download_basic_auth_password,
+            maxmind_offline,
+            enable_anonymization,
+            maxmind_anon_db_path,
+            maxmind_asn_db_path,
+            maxmind_domain_db_path,
+            maxmind_lookup_timeout_ms,
+            trace_not_found,
+            geo_overrides,
+            timezone_fallback_csv,
         })
     }
 }
@@ -137,6 +216,47 @@ pub(crate) fn read_env_first(keys: &[&str]) -> Option<String> {
     None
 }
 
+pub(crate) fn read_env_flag(key: &str) -> bool {
+    env::var(key)
+        .map(|value| {
+            let trimmed = value.trim();
+            !trimmed.is_empty() && trimmed != "0"
+        })
+        .unwrap_or(false)
+}
+
+/// Parses `GEOIP_OVERRIDES`, a JSON object mapping IPs to `GeoPoint`s.
+fn read_geo_overrides() -> HashMap<String, GeoPoint> {
+    match read_env_first(&["GEOIP_OVERRIDES"]) {
+        Some(raw) => parse_geo_overrides(&raw),
+        None => HashMap::new(),
+    }
+}
+
+/// Parses a `GEOIP_OVERRIDES` value. Entries whose coordinates
+/// [`GeoPoint::new`] rejects are skipped with a warning.
+pub(crate) fn parse_geo_overrides(raw: &str) -> HashMap<String, GeoPoint> {
+    let overrides: HashMap<String, GeoPoint> = match serde_json::from_str(raw) {
+        Ok(overrides) => overrides,
+        Err(err) => {
+            warn!(?err, "GEOIP_OVERRIDES is not a valid JSON object; ignoring");
+            return HashMap::new();
+        }
+    };
+    overrides
+        .into_iter()
+        .filter(
+            |(ip, point)| match GeoPoint::new(point.latitude, point.longitude, None, None) {
+                Ok(_) => true,
+                Err(err) => {
+                    warn!(ip, %err, "ignoring GEOIP_OVERRIDES entry with invalid coordinates");
+                    false
+                }
+            },
+        )
+        .collect()
+}
+
 pub(crate) fn derive_ws_url(rpc_url: &str) -> String {
     let mut url = match url::Url::parse(rpc_url) {
         Ok(url) => url,
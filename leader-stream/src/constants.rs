@@ -14,4 +14,7 @@ pub const NEXT_LEADERS_DEFAULT_LIMIT: usize = 1000;
 pub const NEXT_LEADERS_MIN_LIMIT: usize = 1;
 pub const NEXT_LEADERS_MAX_LIMIT: usize = 5000;
 pub const INITIAL_PAYLOAD_LIMIT: usize = 250;
+pub const DEFAULT_MAXMIND_DOWNLOAD_TIMEOUT_MS: u64 = 30000;
+pub const DEFAULT_MAXMIND_CONNECT_TIMEOUT_MS: u64 = 5000;
+pub const DEFAULT_MAXMIND_MAX_DOWNLOAD_BYTES: u64 = 500 * 1024 * 1024;
 pub const DEFAULT_STATIC_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/public");
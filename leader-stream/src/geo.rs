@@ -1,105 +1,2914 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
+use std::future::{Future, IntoFuture};
 use std::io::{Cursor, Read};
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Context, Result};
 use flate2::read::GzDecoder;
-use maxminddb::geoip2::City;
+use maxminddb::geoip2::{AnonymousIp, Asn, City, Domain};
 use maxminddb::{MaxMindDbError, Reader};
+use parking_lot::RwLock;
 use reqwest::Client;
+use serde::{Deserialize, Serialize};
 use tar::Archive;
-use tokio::sync::RwLock;
-use tracing::{info, warn};
+use tokio::sync::{broadcast, watch, Semaphore};
+use tracing::{debug, info, info_span, warn, Instrument, Span};
 
 use crate::config::Config;
+use crate::constants::{DEFAULT_MAXMIND_DOWNLOAD_TIMEOUT_MS, DEFAULT_MAXMIND_MAX_DOWNLOAD_BYTES};
 
-#[derive(Clone, Debug)]
+const DATABASE_UPDATE_CHANNEL_CAPACITY: usize = 16;
+const EARTH_RADIUS_KM: f64 = 6371.0;
+const DEFAULT_DNS_CACHE_TTL: Duration = Duration::from_secs(60);
+/// Most hostnames [`GeoIpService::lookup_domain`] keeps resolved at once;
+/// the oldest is dropped to make room for a new one.
+pub(crate) const MAX_DNS_CACHE_ENTRIES: usize = 4_096;
+/// Size of the equirectangular map [`GeoPoint::to_svg_pin`] projects onto.
+const SVG_MAP_WIDTH: f64 = 1000.0;
+const SVG_MAP_HEIGHT: f64 = 500.0;
+/// Upper bound on addresses resolved by one [`GeoIpService::lookup_range`].
+pub(crate) const MAX_LOOKUP_RANGE: usize = 65_536;
+/// Region key for IPs without the requested geo data.
+pub(crate) const UNKNOWN_REGION: &str = "Unknown";
+/// Upper bound on entries serialized by one [`GeoIpService::as_json_map`].
+pub(crate) const MAX_JSON_MAP_ENTRIES: usize = 10_000;
+/// Largest frame accepted on the cache socket; an IP or one JSON point is far
+/// smaller, so anything bigger means a confused peer.
+#[cfg(feature = "ipc")]
+const MAX_IPC_FRAME_BYTES: u32 = 64 * 1024;
+/// Free MaxMind test database fetched when no download source is configured.
+const DEFAULT_FALLBACK_DATABASE_URL: &str =
+    "https://raw.githubusercontent.com/maxmind/MaxMind-DB/main/test-data/GeoLite2-City-Test.mmdb";
+/// Size past which the audit log from [`GeoIpService::with_audit_log`] is
+/// rotated, unless overridden with
+/// [`GeoIpService::with_max_audit_log_bytes`].
+#[cfg(feature = "tokio-io")]
+const DEFAULT_MAX_AUDIT_LOG_BYTES: u64 = 64 * 1024 * 1024;
+/// How often a gRPC health `Watch` stream re-checks
+/// [`GeoIpService::healthy`].
+#[cfg(feature = "grpc")]
+const HEALTH_WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Characters escaped in [`GeoPoint::to_uri_encoded_coords`] values: those
+/// that would end or split a query parameter. `-` and `.` stay literal.
+const COORD_QUERY_ENCODE_SET: &percent_encoding::AsciiSet = &percent_encoding::CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'&')
+    .add(b'+')
+    .add(b'<')
+    .add(b'=')
+    .add(b'>');
+
+/// When the last Nominatim request finished, shared by every
+/// [`GeoPoint::fetch_address`] call in the process.
+#[cfg(feature = "nominatim")]
+static NOMINATIM_LAST_REQUEST: tokio::sync::Mutex<Option<Instant>> =
+    tokio::sync::Mutex::const_new(None);
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub(crate) struct GeoPoint {
     pub(crate) latitude: f64,
     pub(crate) longitude: f64,
     pub(crate) city: Option<String>,
+    /// First-level subdivision (state, province, region), when known.
+    #[serde(default)]
+    pub(crate) subdivision: Option<String>,
     pub(crate) country: Option<String>,
+    /// ISO 3166-1 alpha-2 code of `country`, e.g. `DE`.
+    #[serde(default)]
+    pub(crate) country_iso_code: Option<String>,
+    #[serde(default)]
+    pub(crate) continent: Option<String>,
+    /// Anonymity flags from the optional Anonymous IP database. `None` means
+    /// that database is not loaded; once it is, unlisted IPs report `false`.
+    #[serde(default)]
+    pub(crate) is_anonymous: Option<bool>,
+    #[serde(default)]
+    pub(crate) is_vpn: Option<bool>,
+    #[serde(default)]
+    pub(crate) is_tor: Option<bool>,
+    /// Whether the IP is on an anycast network, so its location says little
+    /// about where a given client's traffic actually lands.
+    #[serde(default)]
+    pub(crate) is_anycast: Option<bool>,
+    /// MaxMind's radius, in km, around the coordinates within which the IP
+    /// is likely located.
+    #[serde(default)]
+    pub(crate) accuracy_radius_km: Option<u16>,
+    /// IANA time zone of the location, e.g. `Europe/Berlin`.
+    #[serde(default)]
+    pub(crate) timezone: Option<String>,
+}
+
+/// Linear round-trip latency model for [`GeoPoint::estimated_latency_ms`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct LatencyModel {
+    /// Fixed cost of a round trip regardless of distance.
+    pub(crate) base_ms: f64,
+    pub(crate) ms_per_km: f64,
+}
+
+impl Default for LatencyModel {
+    /// 1 ms plus 0.01 ms/km, roughly light in fiber both ways.
+    fn default() -> Self {
+        Self {
+            base_ms: 1.0,
+            ms_per_km: 0.01,
+        }
+    }
+}
+
+/// Why [`GeoPoint::new`] rejected a coordinate pair.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum GeoPointError {
+    InvalidLatitude(f64),
+    InvalidLongitude(f64),
+}
+
+impl fmt::Display for GeoPointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLatitude(value) => write!(f, "latitude {value} is outside -90..=90"),
+            Self::InvalidLongitude(value) => write!(f, "longitude {value} is outside -180..=180"),
+        }
+    }
+}
+
+impl std::error::Error for GeoPointError {}
+
+impl GeoPoint {
+    /// Builds a point without anonymity flags, rejecting coordinates that
+    /// cannot exist on the globe (including NaN).
+    pub(crate) fn new(
+        latitude: f64,
+        longitude: f64,
+        city: Option<String>,
+        country: Option<String>,
+    ) -> Result<GeoPoint, GeoPointError> {
+        if !(-90.0..=90.0).contains(&latitude) {
+            return Err(GeoPointError::InvalidLatitude(latitude));
+        }
+        if !(-180.0..=180.0).contains(&longitude) {
+            return Err(GeoPointError::InvalidLongitude(longitude));
+        }
+        Ok(GeoPoint {
+            latitude,
+            longitude,
+            city,
+            subdivision: None,
+            country,
+            country_iso_code: None,
+            continent: None,
+            is_anonymous: None,
+            is_vpn: None,
+            is_tor: None,
+            is_anycast: None,
+            accuracy_radius_km: None,
+            timezone: None,
+        })
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn latitude(&self) -> f64 {
+        self.latitude
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn longitude(&self) -> f64 {
+        self.longitude
+    }
+
+    pub(crate) fn city(&self) -> Option<&str> {
+        self.city.as_deref()
+    }
+
+    pub(crate) fn country(&self) -> Option<&str> {
+        self.country.as_deref()
+    }
+
+    /// `[latitude, longitude]` for compact wire formats; every other field is
+    /// dropped.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn to_compact_array(&self) -> [f64; 2] {
+        [self.latitude, self.longitude]
+    }
+
+    /// Inverse of [`to_compact_array`](Self::to_compact_array); the result
+    /// has no city, country, or anonymity data.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn from_compact_array(arr: [f64; 2]) -> GeoPoint {
+        GeoPoint {
+            latitude: arr[0],
+            longitude: arr[1],
+            city: None,
+            subdivision: None,
+            country: None,
+            country_iso_code: None,
+            continent: None,
+            is_anonymous: None,
+            is_vpn: None,
+            is_tor: None,
+            is_anycast: None,
+            accuracy_radius_km: None,
+            timezone: None,
+        }
+    }
+
+    /// Nominatim reverse-geocoding URL for these coordinates, for eyeballing
+    /// a lookup result while debugging.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn to_nominatim_url(&self) -> String {
+        format!(
+            "https://nominatim.openstreetmap.org/reverse?lat={}&lon={}&format=json",
+            self.latitude, self.longitude
+        )
+    }
+
+    /// `lat=<latitude>&lon=<longitude>` query parameters, e.g.
+    /// `lat=51.507&lon=-0.128`, with each value percent-encoded so it
+    /// cannot break URL parsing. Coordinates keep their full precision.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn to_uri_encoded_coords(&self) -> String {
+        let encode = |value: f64| {
+            percent_encoding::utf8_percent_encode(&value.to_string(), COORD_QUERY_ENCODE_SET)
+                .to_string()
+        };
+        format!(
+            "lat={}&lon={}",
+            encode(self.latitude),
+            encode(self.longitude)
+        )
+    }
+
+    /// SVG `<circle>` marking this point on a [`SVG_MAP_WIDTH`] x
+    /// [`SVG_MAP_HEIGHT`] equirectangular world map, followed by a `<text>`
+    /// element when `label` is given. The label is XML-escaped.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn to_svg_pin(&self, label: Option<&str>) -> String {
+        let x = (self.longitude + 180.0) / 360.0 * SVG_MAP_WIDTH;
+        let y = (90.0 - self.latitude) / 180.0 * SVG_MAP_HEIGHT;
+        let mut svg = format!(r#"<circle cx="{x:.2}" cy="{y:.2}" r="4" class="pin"/>"#);
+        if let Some(label) = label {
+            svg.push_str(&format!(
+                r#"<text x="{:.2}" y="{:.2}" class="pin-label">{}</text>"#,
+                x + 6.0,
+                y + 4.0,
+                xml_escape(label)
+            ));
+        }
+        svg
+    }
+
+    /// Street address for these coordinates from Nominatim's reverse
+    /// geocoding API (its `display_name`), or `None` when it has none.
+    ///
+    /// The public instance's usage policy allows at most one request per
+    /// second from an application and requires a `User-Agent` that identifies
+    /// it, so `http_client` must set one. Requests from this process are
+    /// serialized and spaced at least `min_interval` apart (use one second or
+    /// more for the public instance); results should be cached by the caller.
+    #[cfg(feature = "nominatim")]
+    // Only reaches the public instance; tests cover `fetch_nominatim_address`.
+    #[allow(dead_code)]
+    pub(crate) async fn fetch_address(
+        &self,
+        http_client: &Client,
+        min_interval: Duration,
+    ) -> Result<Option<String>> {
+        fetch_nominatim_address(http_client, &self.to_nominatim_url(), min_interval).await
+    }
+
+    /// Degrees-minutes-seconds rendering rounded to the nearest second, e.g.
+    /// `52°31′12″N 13°24′18″E`.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn to_dms(&self) -> String {
+        fn dms(value: f64, positive: char, negative: char) -> String {
+            let total_seconds = (value.abs() * 3600.0).round() as u64;
+            let hemisphere = if value < 0.0 && total_seconds > 0 {
+                negative
+            } else {
+                positive
+            };
+            format!(
+                "{}°{}′{}″{}",
+                total_seconds / 3600,
+                (total_seconds / 60) % 60,
+                total_seconds % 60,
+                hemisphere
+            )
+        }
+        format!(
+            "{} {}",
+            dms(self.latitude, 'N', 'S'),
+            dms(self.longitude, 'E', 'W')
+        )
+    }
+
+    /// One-line `"City, Subdivision, Country"` label, skipping absent parts
+    /// and repeats (e.g. a city named after its state).
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn administrative_area(&self) -> String {
+        let mut parts: Vec<&str> = Vec::with_capacity(3);
+        for part in [&self.city, &self.subdivision, &self.country] {
+            let Some(part) = part
+                .as_deref()
+                .map(str::trim)
+                .filter(|part| !part.is_empty())
+            else {
+                continue;
+            };
+            if !parts.iter().any(|kept| kept.eq_ignore_ascii_case(part)) {
+                parts.push(part);
+            }
+        }
+        parts.join(", ")
+    }
+
+    /// Country-level copy of this point: the city and subdivision are dropped
+    /// and coordinates are rounded to one decimal place (roughly 11 km).
+    pub(crate) fn anonymized(&self) -> GeoPoint {
+        GeoPoint {
+            latitude: (self.latitude * 10.0).round() / 10.0,
+            longitude: (self.longitude * 10.0).round() / 10.0,
+            city: None,
+            subdivision: None,
+            ..self.clone()
+        }
+    }
+
+    /// Copy of this point with both coordinates rounded to the nearest
+    /// multiple of `grid_degrees`, so every point in a grid cell reports the
+    /// same location. Results are clamped to the valid coordinate range; a
+    /// non-positive or non-finite grid leaves the coordinates unchanged.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn snap_to_grid(&self, grid_degrees: f64) -> GeoPoint {
+        if !(grid_degrees.is_finite() && grid_degrees > 0.0) {
+            return self.clone();
+        }
+        let snap = |value: f64| (value / grid_degrees).round() * grid_degrees;
+        GeoPoint {
+            latitude: snap(self.latitude).clamp(-90.0, 90.0),
+            longitude: snap(self.longitude).clamp(-180.0, 180.0),
+            ..self.clone()
+        }
+    }
+
+    /// True when both coordinates are within `tolerance_deg` degrees.
+    pub(crate) fn approx_eq(&self, other: &GeoPoint, tolerance_deg: f64) -> bool {
+        (self.latitude - other.latitude).abs() <= tolerance_deg
+            && (self.longitude - other.longitude).abs() <= tolerance_deg
+    }
+
+    /// Removes consecutive points that are [`approx_eq`](Self::approx_eq) to
+    /// the point kept before them, compressing traces before they are sent
+    /// to a frontend.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn dedup_by_location(points: &mut Vec<GeoPoint>, tolerance_deg: f64) {
+        points.dedup_by(|current, kept| current.approx_eq(kept, tolerance_deg));
+    }
+
+    /// Great-circle (haversine) distance to `other` in kilometres.
+    pub(crate) fn distance_km(&self, other: &GeoPoint) -> f64 {
+        let lat1 = self.latitude.to_radians();
+        let lat2 = other.latitude.to_radians();
+        let delta_lat = lat2 - lat1;
+        let delta_lon = (other.longitude - self.longitude).to_radians();
+        let a = (delta_lat / 2.0).sin().powi(2)
+            + lat1.cos() * lat2.cos() * (delta_lon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+    }
+
+    /// Rough round-trip latency to `other` in milliseconds from the
+    /// great-circle distance: `base_ms + distance_km * ms_per_km`. Real
+    /// routes are longer than great circles and add queuing, so treat this
+    /// as a lower-bound heuristic for ranking, not a substitute for pings.
+    pub(crate) fn estimated_latency_ms(&self, other: &GeoPoint, model: LatencyModel) -> f64 {
+        model.base_ms + self.distance_km(other) * model.ms_per_km
+    }
+
+    /// Weighted centre of `points`, averaged on the unit sphere so clusters
+    /// straddling the antimeridian stay in place. Returns `None` when there
+    /// is no positive weight or the points cancel out (e.g. antipodes).
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn weighted_centroid(points: &[(GeoPoint, f64)]) -> Option<GeoPoint> {
+        let (mut x, mut y, mut z, mut total) = (0.0, 0.0, 0.0, 0.0);
+        for (point, weight) in points.iter().filter(|(_, weight)| *weight > 0.0) {
+            let lat = point.latitude.to_radians();
+            let lon = point.longitude.to_radians();
+            x += weight * lat.cos() * lon.cos();
+            y += weight * lat.cos() * lon.sin();
+            z += weight * lat.sin();
+            total += weight;
+        }
+        if total == 0.0 {
+            return None;
+        }
+        let (x, y, z) = (x / total, y / total, z / total);
+        let horizontal = x.hypot(y);
+        if horizontal.hypot(z) < 1e-9 {
+            return None;
+        }
+        GeoPoint::new(
+            z.atan2(horizontal).to_degrees(),
+            y.atan2(x).to_degrees(),
+            None,
+            None,
+        )
+        .ok()
+    }
+
+    /// Initial great-circle bearing from this point to `other`, in degrees
+    /// clockwise from north (0-360).
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn bearing_to(&self, other: &GeoPoint) -> f64 {
+        let lat1 = self.latitude.to_radians();
+        let lat2 = other.latitude.to_radians();
+        let delta_lon = (other.longitude - self.longitude).to_radians();
+        let y = delta_lon.sin() * lat2.cos();
+        let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * delta_lon.cos();
+        y.atan2(x).to_degrees().rem_euclid(360.0)
+    }
+
+    /// Returns the candidate whose city name is closest to `query` by
+    /// case-insensitive Levenshtein distance, or `None` when even the best
+    /// match differs in more than half of `query`'s length.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn fuzzy_match_city<'a>(
+        candidates: &'a [GeoPoint],
+        query: &str,
+    ) -> Option<&'a GeoPoint> {
+        let query_lower = query.to_lowercase();
+        let (distance, best) = candidates
+            .iter()
+            .filter_map(|point| {
+                let city = point.city.as_deref()?;
+                Some((
+                    strsim::levenshtein(&city.to_lowercase(), &query_lower),
+                    point,
+                ))
+            })
+            .min_by_key(|(distance, _)| *distance)?;
+        (distance <= query.len() / 2).then_some(best)
+    }
+
+    /// Encodes this point as a full-length (10 digit) Open Location Code,
+    /// precise to roughly 14 m.
+    #[cfg(feature = "openlocationcode")]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn to_plus_code(&self) -> String {
+        open_location_code::encode(olc_geo::Point::new(self.longitude, self.latitude), 10)
+    }
+
+    /// Returns the H3 cell containing this point at `resolution` (0-15), or
+    /// `None` when the resolution or coordinates are out of range.
+    #[cfg(feature = "h3")]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn to_h3_cell(&self, resolution: u8) -> Option<h3o::CellIndex> {
+        if !(-90.0..=90.0).contains(&self.latitude) || !(-180.0..=180.0).contains(&self.longitude) {
+            return None;
+        }
+        let resolution = h3o::Resolution::try_from(resolution).ok()?;
+        let coord = h3o::LatLng::new(self.latitude, self.longitude).ok()?;
+        Some(coord.to_cell(resolution))
+    }
+
+    /// Converts `utc` to this point's local time, or `None` when the point
+    /// has no time zone or it is not a known IANA zone.
+    #[cfg(feature = "chrono-tz")]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn to_local_datetime(
+        &self,
+        utc: chrono::DateTime<chrono::Utc>,
+    ) -> Option<chrono::DateTime<chrono_tz::Tz>> {
+        let tz: chrono_tz::Tz = self.timezone.as_deref()?.parse().ok()?;
+        Some(utc.with_timezone(&tz))
+    }
+
+    /// Geohash of this point with `precision` characters (1-12), or `None`
+    /// when the precision or coordinates are out of range.
+    #[cfg(feature = "geohash")]
+    pub(crate) fn to_geohash(&self, precision: usize) -> Option<String> {
+        let coord = geohash::Coord {
+            x: self.longitude,
+            y: self.latitude,
+        };
+        geohash::encode(coord, precision).ok()
+    }
+
+    /// Formats this point as a 1 m precision MGRS reference, or `None` for
+    /// polar latitudes beyond ±84° where MGRS hands over to UPS.
+    #[cfg(feature = "mgrs")]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn to_mgrs(&self) -> Option<String> {
+        if self.latitude.abs() > 84.0 {
+            return None;
+        }
+        let coord = geoconvert::LatLon::create(self.latitude, self.longitude).ok()?;
+        Some(coord.to_mgrs(5).to_string())
+    }
+}
+
+/// Snapshot of what the lookup cache currently knows, for dashboards.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct GeoStats {
+    pub(crate) unique_countries: usize,
+    pub(crate) unique_cities: usize,
+    pub(crate) total_cached: usize,
+    /// Share of cached IPs that resolved to a location, as a percentage.
+    pub(crate) coverage_percent: f64,
+}
+
+/// How many of a set of IPs the current City database can place; see
+/// [`GeoIpService::coverage_check`].
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CoverageReport {
+    pub(crate) total: usize,
+    pub(crate) found: usize,
+    pub(crate) not_found: usize,
+    /// `found` as a percentage of `total`; `0.0` for an empty input.
+    pub(crate) coverage_pct: f64,
+}
+
+/// Anonymity flags for one IP from a MaxMind Anonymous IP database. IPs the
+/// database does not list report every flag as `false`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct AnonymousIpInfo {
+    pub(crate) is_anonymous: bool,
+    pub(crate) is_vpn: bool,
+    pub(crate) is_tor: bool,
+    /// Public or residential proxy.
+    pub(crate) is_proxy: bool,
+}
+
+impl AnonymousIpInfo {
+    fn from_record(record: &AnonymousIp) -> Self {
+        Self {
+            is_anonymous: record.is_anonymous.unwrap_or(false),
+            is_vpn: record.is_anonymous_vpn.unwrap_or(false),
+            is_tor: record.is_tor_exit_node.unwrap_or(false),
+            is_proxy: record.is_public_proxy.unwrap_or(false)
+                || record.is_residential_proxy.unwrap_or(false),
+        }
+    }
+}
+
+/// Autonomous system that announces an IP, from a MaxMind ASN database.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct AsnInfo {
+    pub(crate) number: u32,
+    pub(crate) organization: Option<String>,
+}
+
+/// City, ASN, Anonymous IP, and Domain answers for one IP; see
+/// [`GeoIpService::lookup_enriched`]. Each part is `None` when its database
+/// is not loaded or has no record.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) struct EnrichedGeoPoint {
+    pub(crate) geo: Option<GeoPoint>,
+    pub(crate) asn: Option<AsnInfo>,
+    pub(crate) anon: Option<AnonymousIpInfo>,
+    /// Registered domain from the MaxMind Domain database; see
+    /// [`GeoIpService::lookup_registered_domain`].
+    pub(crate) registered_domain: Option<String>,
+}
+
+/// Sent to [`GeoIpService::watch_updates`] subscribers after the database
+/// reader is replaced.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) struct DatabaseUpdatedEvent {
+    pub(crate) reload_time: SystemTime,
+    pub(crate) new_node_count: u32,
+}
+
+/// Where two IPs are relative to each other; see
+/// [`GeoIpService::lookup_compare`]. The `same_*` flags are `false` unless
+/// both sides report the field.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) struct GeoCompareResult {
+    pub(crate) same_country: bool,
+    /// Same city name within the same country.
+    pub(crate) same_city: bool,
+    /// Great-circle distance, when both IPs resolved.
+    pub(crate) distance_km: Option<f64>,
+    pub(crate) a: Option<GeoPoint>,
+    pub(crate) b: Option<GeoPoint>,
+}
+
+/// Region granularity for [`GeoIpService::build_routing_table`] and
+/// [`GeoIpService::count_by_region`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) enum GroupBy {
+    Country,
+    /// Keyed as `"City, Country"` so same-named cities stay apart.
+    City,
+    Continent,
+}
+
+impl GroupBy {
+    /// Region name for `point`, or `"Unknown"` when it is unresolved or
+    /// lacks the field.
+    fn region(self, point: Option<&GeoPoint>) -> String {
+        let region = point.and_then(|point| match self {
+            GroupBy::Country => point.country.clone(),
+            GroupBy::City => point.city.as_deref().map(|city| match point.country() {
+                Some(country) => format!("{city}, {country}"),
+                None => city.to_string(),
+            }),
+            GroupBy::Continent => point.continent.clone(),
+        });
+        region.unwrap_or_else(|| UNKNOWN_REGION.to_string())
+    }
+}
+
+/// Outcome of [`GeoIpService::lookup_diff`].
+#[derive(Clone, Debug)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) struct GeoLookupDiff {
+    /// What the last differing `lookup_diff` for this IP recorded.
+    pub(crate) previous: Option<GeoPoint>,
+    pub(crate) current: Option<GeoPoint>,
+    pub(crate) changed: bool,
+}
+
+/// Published by [`GeoIpService::lookup_and_publish`] for every IP freshly
+/// resolved from the database.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) struct GeoEvent {
+    pub(crate) ip: String,
+    pub(crate) point: GeoPoint,
+}
+
+/// An IP paired with the caller's payload, as fed to
+/// [`GeoIpService::annotate_sink`].
+#[cfg(feature = "sink")]
+pub(crate) type IpWithPayload<T> = (String, T);
+
+/// Metadata of the loaded City database, from [`GeoIpService::database_info`].
+#[derive(Clone, Debug)]
+pub(crate) struct DatabaseInfo {
+    pub(crate) database_type: String,
+    /// Build time as seconds since the Unix epoch.
+    pub(crate) build_epoch: u64,
+    pub(crate) node_count: u32,
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) ip_version: u16,
+}
+
+impl DatabaseInfo {
+    fn from_reader(reader: &Reader<Vec<u8>>) -> Self {
+        Self {
+            database_type: reader.metadata.database_type.clone(),
+            build_epoch: reader.metadata.build_epoch,
+            node_count: reader.metadata.node_count,
+            ip_version: reader.metadata.ip_version,
+        }
+    }
+
+    /// Time since the database was built; zero if the build time is in the
+    /// future.
+    pub(crate) fn age(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .saturating_sub(Duration::from_secs(self.build_epoch))
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn is_stale(&self, max_age: Duration) -> bool {
+        self.age() > max_age
+    }
+}
+
+/// Where a [`GeoIpService::lookup_with_metadata`] answer came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum GeoSource {
+    /// Served from the lookup cache, including cached misses.
+    Cache,
+    /// Resolved from the database just now.
+    Live,
+    /// Not resolvable: empty or invalid IP, no database, or no record.
+    NotFound,
+    /// The [`GeoIpService::with_lookup_timeout`] deadline passed before the
+    /// database answered; nothing was cached.
+    TimedOut,
+}
+
+type SharedReader = Arc<Reader<Vec<u8>>>;
+
+const BREAKER_CLOSED: u8 = 0;
+const BREAKER_OPEN: u8 = 1;
+const BREAKER_HALF_OPEN: u8 = 2;
+
+/// Outcome of [`CircuitBreaker::allow`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BreakerAdmission {
+    Closed,
+    /// This caller won the half-open probe and must release it if the
+    /// lookup never reaches the reader.
+    Probe,
+    Rejected,
+}
+
+/// Trips after `failure_threshold` consecutive City reader errors; see
+/// [`GeoIpService::lookup_with_circuit_breaker`].
+struct CircuitBreaker {
+    state: AtomicU8,
+    consecutive_failures: AtomicU32,
+    /// When the breaker last opened, in milliseconds since `epoch`.
+    opened_at_ms: AtomicU64,
+    epoch: Instant,
+    failure_threshold: u32,
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            state: AtomicU8::new(BREAKER_CLOSED),
+            consecutive_failures: AtomicU32::new(0),
+            opened_at_ms: AtomicU64::new(0),
+            epoch: Instant::now(),
+            failure_threshold: failure_threshold.max(1),
+            cooldown,
+        }
+    }
+
+    /// Whether a lookup may read the database. Once the cooldown has passed,
+    /// an open breaker goes half-open and admits exactly one caller as the
+    /// probe; only that caller sees [`BreakerAdmission::Probe`].
+    fn allow(&self) -> BreakerAdmission {
+        match self.state.load(Ordering::SeqCst) {
+            BREAKER_CLOSED => BreakerAdmission::Closed,
+            BREAKER_OPEN => {
+                let open_ms = (self.epoch.elapsed().as_millis() as u64)
+                    .saturating_sub(self.opened_at_ms.load(Ordering::SeqCst));
+                let probe = open_ms >= self.cooldown.as_millis() as u64
+                    && self
+                        .state
+                        .compare_exchange(
+                            BREAKER_OPEN,
+                            BREAKER_HALF_OPEN,
+                            Ordering::SeqCst,
+                            Ordering::SeqCst,
+                        )
+                        .is_ok();
+                if probe {
+                    BreakerAdmission::Probe
+                } else {
+                    BreakerAdmission::Rejected
+                }
+            }
+            _ => BreakerAdmission::Rejected,
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.state.load(Ordering::SeqCst) == BREAKER_OPEN
+    }
+
+    fn record_success(&self) {
+        self.consecutive_failures.store(0, Ordering::SeqCst);
+        self.state.store(BREAKER_CLOSED, Ordering::SeqCst);
+    }
+
+    fn record_failure(&self) {
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::SeqCst) + 1;
+        let state = self.state.load(Ordering::SeqCst);
+        if state == BREAKER_HALF_OPEN
+            || (state == BREAKER_CLOSED && failures >= self.failure_threshold)
+        {
+            self.opened_at_ms
+                .store(self.epoch.elapsed().as_millis() as u64, Ordering::SeqCst);
+            self.state.store(BREAKER_OPEN, Ordering::SeqCst);
+            warn!(
+                failures,
+                cooldown_ms = self.cooldown.as_millis() as u64,
+                "MaxMind reader circuit breaker opened"
+            );
+        }
+    }
+
+    /// Re-opens a half-open breaker whose probe never reached the reader (a
+    /// cache hit, invalid IP, or timeout), so the next caller probes instead.
+    fn release_probe(&self) {
+        let _ = self.state.compare_exchange(
+            BREAKER_HALF_OPEN,
+            BREAKER_OPEN,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+    }
+}
+
+/// Append-mode JSON-lines file for [`GeoIpService::with_audit_log`].
+#[cfg(feature = "tokio-io")]
+struct AuditLog {
+    path: PathBuf,
+    /// The open file and its current length in bytes.
+    file: tokio::sync::Mutex<(tokio::fs::File, u64)>,
+}
+
+#[cfg(feature = "tokio-io")]
+impl AuditLog {
+    async fn open(path: PathBuf) -> Result<Self> {
+        let file = open_append(&path).await?;
+        let len = file
+            .metadata()
+            .await
+            .with_context(|| format!("failed to stat audit log {}", path.display()))?
+            .len();
+        Ok(Self {
+            path,
+            file: tokio::sync::Mutex::new((file, len)),
+        })
+    }
+
+    /// Appends `line` and, once the file is larger than `max_bytes`, moves
+    /// it to `<path>.1` (replacing any earlier rotation) and starts a fresh
+    /// file. Failures are logged; auditing never fails a lookup.
+    async fn append(&self, line: &str, max_bytes: u64) {
+        use tokio::io::AsyncWriteExt;
+
+        let mut guard = self.file.lock().await;
+        let (file, len) = &mut *guard;
+        // tokio's `File` hands writes to a blocking task; flush so each
+        // record is on disk once the lookup returns.
+        let written = async {
+            file.write_all(line.as_bytes()).await?;
+            file.flush().await
+        };
+        if let Err(err) = written.await {
+            warn!(?err, path = %self.path.display(), "failed to append geolocation audit record");
+            return;
+        }
+        *len += line.len() as u64;
+        if *len <= max_bytes {
+            return;
+        }
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        let reopened = async {
+            tokio::fs::rename(&self.path, &rotated).await?;
+            open_append(&self.path).await
+        };
+        match reopened.await {
+            Ok(fresh) => {
+                *file = fresh;
+                *len = 0;
+            }
+            Err(err) => {
+                warn!(?err, path = %self.path.display(), "failed to rotate geolocation audit log");
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+async fn open_append(path: &Path) -> Result<tokio::fs::File> {
+    tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("failed to open audit log {}", path.display()))
+}
+
+/// Deferred [`load_geoip`] for [`GeoIpService::new_lazy`].
+struct LazyReader {
+    config: Arc<Config>,
+    /// Set while a load task runs, so concurrent lookups start only one.
+    loading: AtomicBool,
+    /// Wakes lookups waiting on the load task once it finishes.
+    loaded: tokio::sync::Notify,
 }
 
-#[derive(Clone)]
-pub(crate) struct GeoIpService {
-    reader: Option<Arc<Reader<Vec<u8>>>>,
-    cache: Arc<RwLock<HashMap<String, Option<GeoPoint>>>>,
-    lookup_error_logged: Arc<AtomicBool>,
-}
+#[derive(Clone)]
+pub(crate) struct GeoIpService {
+    // Shared across clones so a swap is seen by every handle; lookups clone
+    // the inner `Arc` and never hold the lock while reading.
+    reader: Arc<RwLock<Option<SharedReader>>>,
+    /// When the current reader was installed, in seconds since the Unix
+    /// epoch; `0` before any database is loaded.
+    reader_loaded_at: Arc<AtomicU64>,
+    anon_reader: Option<Arc<Reader<Vec<u8>>>>,
+    asn_reader: Option<SharedReader>,
+    domain_reader: Option<SharedReader>,
+    cache: Arc<RwLock<HashMap<String, Option<GeoPoint>>>>,
+    lookup_error_logged: Arc<AtomicBool>,
+    total_lookups: Arc<AtomicU64>,
+    total_cache_hits: Arc<AtomicU64>,
+    /// City reader errors, as opposed to addresses missing from the database.
+    reader_errors: Arc<AtomicU64>,
+    lookup_limit: Option<Arc<Semaphore>>,
+    lookup_timeout: Option<Duration>,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    not_found_counts: Option<Arc<parking_lot::Mutex<HashMap<IpAddr, u64>>>>,
+    reader_refresh_callback: Option<Arc<dyn Fn() + Send + Sync>>,
+    updates: broadcast::Sender<DatabaseUpdatedEvent>,
+    events: Option<broadcast::Sender<GeoEvent>>,
+    /// Live answers per IP for [`subscribe`](Self::subscribe).
+    subscriptions: Arc<RwLock<HashMap<String, watch::Sender<Option<GeoPoint>>>>>,
+    dns_cache: Arc<RwLock<HashMap<String, (IpAddr, Instant)>>>,
+    dns_ttl: Duration,
+    /// Per-tenant fixed answers, keyed by tenant ID and then IP.
+    tenant_overrides: Arc<RwLock<HashMap<String, HashMap<String, GeoPoint>>>>,
+    /// Lowercased country names rejected by
+    /// [`filter_stream`](Self::filter_stream).
+    blocked_countries: Arc<HashSet<String>>,
+    /// Provider name per network from
+    /// [`annotate_cloud_ranges`](Self::annotate_cloud_ranges), kept out of
+    /// the lookup cache.
+    cloud_ranges: Arc<RwLock<Vec<(ipnet::IpNet, String)>>>,
+    /// IANA time zones keyed by lowercased city name, used when a City
+    /// record has no `time_zone`.
+    timezone_fallback: Arc<HashMap<String, String>>,
+    /// Last answer per IP seen by [`lookup_diff`](Self::lookup_diff).
+    diff_history: Arc<RwLock<HashMap<String, Option<GeoPoint>>>>,
+    lazy_reader: Option<Arc<LazyReader>>,
+    #[cfg(feature = "tokio-io")]
+    audit_log: Option<Arc<AuditLog>>,
+    #[cfg(feature = "tokio-io")]
+    max_audit_log_bytes: u64,
+    #[cfg(feature = "ipc")]
+    cache_socket: Arc<tokio::sync::Mutex<Option<tokio::net::UnixStream>>>,
+}
+
+impl GeoIpService {
+    pub(crate) fn from_reader(reader: Reader<Vec<u8>>) -> Self {
+        let service = Self::new_noop();
+        service.install_reader(Arc::new(reader));
+        service
+    }
+
+    /// A service without any database: every lookup resolves to `None`
+    /// without I/O, e.g. for tests or deployments that skip geolocation.
+    pub(crate) fn new_noop() -> Self {
+        Self {
+            reader: Arc::new(RwLock::new(None)),
+            reader_loaded_at: Arc::new(AtomicU64::new(0)),
+            anon_reader: None,
+            asn_reader: None,
+            domain_reader: None,
+            cache: Arc::new(RwLock::new(HashMap::new())),
+            lookup_error_logged: Arc::new(AtomicBool::new(false)),
+            total_lookups: Arc::new(AtomicU64::new(0)),
+            total_cache_hits: Arc::new(AtomicU64::new(0)),
+            reader_errors: Arc::new(AtomicU64::new(0)),
+            lookup_limit: None,
+            lookup_timeout: None,
+            circuit_breaker: None,
+            not_found_counts: None,
+            reader_refresh_callback: None,
+            updates: broadcast::channel(DATABASE_UPDATE_CHANNEL_CAPACITY).0,
+            events: None,
+            subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            dns_cache: Arc::new(RwLock::new(HashMap::new())),
+            dns_ttl: DEFAULT_DNS_CACHE_TTL,
+            tenant_overrides: Arc::new(RwLock::new(HashMap::new())),
+            blocked_countries: Arc::new(HashSet::new()),
+            cloud_ranges: Arc::new(RwLock::new(Vec::new())),
+            timezone_fallback: Arc::new(HashMap::new()),
+            diff_history: Arc::new(RwLock::new(HashMap::new())),
+            lazy_reader: None,
+            #[cfg(feature = "tokio-io")]
+            audit_log: None,
+            #[cfg(feature = "tokio-io")]
+            max_audit_log_bytes: DEFAULT_MAX_AUDIT_LOG_BYTES,
+            #[cfg(feature = "ipc")]
+            cache_socket: Arc::new(tokio::sync::Mutex::new(None)),
+        }
+    }
+
+    /// Opens the database named by the last path segment of `url` inside
+    /// `cache_dir`, downloading it first if it is not cached yet. `.tar.gz`
+    /// and `.tgz` URLs are treated as MaxMind archives, `.gz` as a gzipped
+    /// MMDB, and anything else as a raw MMDB.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn from_url(url: &str, cache_dir: &Path) -> Result<Self> {
+        let (file_name, raw_mmdb) = database_file_name(url)?;
+        fs::create_dir_all(cache_dir).with_context(|| {
+            format!(
+                "failed to create database directory {}",
+                cache_dir.display()
+            )
+        })?;
+        let path = cache_dir.join(file_name);
+        if !path.exists() {
+            info!(
+                "MaxMind database not cached at {}; downloading",
+                path.display()
+            );
+            let client = Client::builder()
+                .timeout(Duration::from_millis(DEFAULT_MAXMIND_DOWNLOAD_TIMEOUT_MS))
+                .build()
+                .context("failed to build HTTP client for database download")?;
+            fetch_and_write(
+                &client,
+                url,
+                &path,
+                raw_mmdb,
+                DEFAULT_MAXMIND_MAX_DOWNLOAD_BYTES,
+                None,
+            )
+            .await?;
+        }
+        let reader = Reader::open_readfile(&path)
+            .with_context(|| format!("failed to open MaxMind database at {}", path.display()))?;
+        Ok(Self::from_reader(reader))
+    }
+
+    /// A service that loads its City database with [`load_geoip`] on the
+    /// first uncached lookup instead of up front. Overrides and lookup
+    /// options from `config` apply immediately. The load runs in its own
+    /// task, so a lookup timeout abandons the wait but not the download, and
+    /// misses cached before it finishes are dropped once it does. A failed
+    /// load is logged and retried by the next lookup.
+    ///
+    /// Only the City database is installed: the Anonymous IP
+    /// (`maxmind_anon_db_path`), ASN (`maxmind_asn_db_path`) and Domain
+    /// (`maxmind_domain_db_path`) databases and the `timezone_fallback_csv`
+    /// time zones are not, so points carry no anonymity flags or fallback
+    /// time zones and ASN and domain lookups find nothing.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn new_lazy(config: Arc<Config>) -> Self {
+        let mut service = Self::new_noop().with_overrides(config.geo_overrides.clone());
+        if config.trace_not_found {
+            service = service.with_not_found_tracing();
+        }
+        if let Some(timeout_ms) = config.maxmind_lookup_timeout_ms {
+            service = service.with_lookup_timeout(Duration::from_millis(timeout_ms));
+        }
+        service.lazy_reader = Some(Arc::new(LazyReader {
+            config,
+            loading: AtomicBool::new(false),
+            loaded: tokio::sync::Notify::new(),
+        }));
+        service
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_static(entries: HashMap<String, Option<GeoPoint>>) -> Self {
+        Self {
+            cache: Arc::new(RwLock::new(entries)),
+            ..Self::new_noop()
+        }
+    }
+
+    /// Serves a MaxMind Anonymous IP database on its own: [`lookup`](Self::lookup)
+    /// finds no points, but [`lookup_anonymous_ip`](Self::lookup_anonymous_ip)
+    /// answers from `reader`.
+    pub(crate) fn from_anonymous_ip_reader(reader: Reader<Vec<u8>>) -> Self {
+        Self::new_noop().with_anonymous_ip_reader(reader)
+    }
+
+    /// Seeds the cache with fixed answers for specific IPs. They are ordinary
+    /// cache entries: lookups are served from the cache first, so database
+    /// results and reloads never replace them, but
+    /// [`load_csv`](Self::load_csv) overwrites entries for the same IP and
+    /// [`drain_cache`](Self::drain_cache) removes them.
+    pub(crate) fn with_overrides(self, overrides: HashMap<String, GeoPoint>) -> Self {
+        self.cache
+            .write()
+            .extend(overrides.into_iter().map(|(ip, point)| (ip, Some(point))));
+        self
+    }
+
+    /// Merges anonymity flags from a MaxMind Anonymous IP database into every
+    /// point resolved by the City reader.
+    pub(crate) fn with_anonymous_ip_reader(mut self, reader: Reader<Vec<u8>>) -> Self {
+        self.anon_reader = Some(Arc::new(reader));
+        self
+    }
+
+    /// Loads a MaxMind ASN database for [`lookup_asn`](Self::lookup_asn) and
+    /// [`lookup_enriched`](Self::lookup_enriched).
+    pub(crate) fn with_asn_reader(mut self, reader: Reader<Vec<u8>>) -> Self {
+        self.asn_reader = Some(Arc::new(reader));
+        self
+    }
+
+    /// Loads a MaxMind Domain database for
+    /// [`lookup_registered_domain`](Self::lookup_registered_domain) and
+    /// [`lookup_enriched`](Self::lookup_enriched).
+    pub(crate) fn with_domain_reader(mut self, reader: Reader<Vec<u8>>) -> Self {
+        self.domain_reader = Some(Arc::new(reader));
+        self
+    }
+
+    /// Countries, by name and compared case-insensitively, whose IPs
+    /// [`filter_stream`](Self::filter_stream) drops. The server itself does
+    /// not filter connections; this is for embedders that call
+    /// `filter_stream` on their own admission path.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn with_blocked_countries(mut self, countries: HashSet<String>) -> Self {
+        self.blocked_countries = Arc::new(
+            countries
+                .iter()
+                .map(|country| country.to_lowercase())
+                .collect(),
+        );
+        self
+    }
+
+    /// IANA time zones by city name, compared case-insensitively, filled in
+    /// for database records that carry no `time_zone` of their own.
+    pub(crate) fn with_timezone_fallback(mut self, timezones: HashMap<String, String>) -> Self {
+        self.timezone_fallback = Arc::new(
+            timezones
+                .into_iter()
+                .map(|(city, timezone)| (city.to_lowercase(), timezone))
+                .collect(),
+        );
+        self
+    }
+
+    /// Caps how many uncached lookups may read the MaxMind database at once;
+    /// callers beyond the limit wait for a permit instead of piling onto the
+    /// reader.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn with_max_concurrent_lookups(self, limit: usize) -> Self {
+        self.with_lookup_limit(Arc::new(Semaphore::new(limit.max(1))))
+    }
+
+    /// Like [`with_max_concurrent_lookups`](Self::with_max_concurrent_lookups),
+    /// with the permits taken from `limit`, so several services can share
+    /// one cap on concurrent database reads.
+    pub(crate) fn with_lookup_limit(mut self, limit: Arc<Semaphore>) -> Self {
+        self.lookup_limit = Some(limit);
+        self
+    }
+
+    /// Gives up on uncached lookups that take longer than `timeout`, resolving
+    /// them to `None`. The deadline covers waiting for a lookup permit, the
+    /// database read and the cache write. A timeout while waiting or reading
+    /// caches nothing, so the IP is looked up again next time; the read itself
+    /// cannot be interrupted and finishes on the blocking pool. A cache write
+    /// already under way when the deadline passes still completes.
+    pub(crate) fn with_lookup_timeout(mut self, timeout: Duration) -> Self {
+        self.lookup_timeout = Some(timeout);
+        self
+    }
+
+    /// Enables the breaker used by
+    /// [`lookup_with_circuit_breaker`](Self::lookup_with_circuit_breaker): it
+    /// opens after `failure_threshold` consecutive City reader errors and
+    /// stays open for `cooldown` before probing the reader again.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn with_circuit_breaker(
+        mut self,
+        failure_threshold: u32,
+        cooldown: Duration,
+    ) -> Self {
+        self.circuit_breaker = Some(Arc::new(CircuitBreaker::new(failure_threshold, cooldown)));
+        self
+    }
+
+    /// How long [`lookup_domain`](Self::lookup_domain) reuses a resolved
+    /// address before asking DNS again. Defaults to 60 seconds.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn with_dns_ttl(mut self, ttl: Duration) -> Self {
+        self.dns_ttl = ttl;
+        self
+    }
+
+    /// Emits a debug event for every IP the database has no record for,
+    /// with how many times that IP has missed so far.
+    pub(crate) fn with_not_found_tracing(mut self) -> Self {
+        self.not_found_counts = Some(Arc::new(parking_lot::Mutex::new(HashMap::new())));
+        self
+    }
+
+    /// Registers `callback` to run after every successful reader swap, so
+    /// embedders can clear caches derived from the old database.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn with_reader_refresh_callback(
+        mut self,
+        callback: Arc<dyn Fn() + Send + Sync>,
+    ) -> Self {
+        self.reader_refresh_callback = Some(callback);
+        self
+    }
+
+    /// Wraps this service in an [`Arc`] for sharing across tasks, e.g.
+    /// `load_geoip(&config).await?.into_arc()`.
+    pub(crate) fn into_arc(self) -> Arc<GeoIpService> {
+        Arc::new(self)
+    }
+
+    /// Sends a [`GeoEvent`] on `sender` for every lookup made through
+    /// [`lookup_and_publish`](Self::lookup_and_publish) that resolves a point
+    /// from the database.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn with_event_channel(mut self, sender: broadcast::Sender<GeoEvent>) -> Self {
+        self.events = Some(sender);
+        self
+    }
+
+    /// Appends a JSON-lines record (`ts`, `ip`, `cache_hit`, `found`) to the
+    /// file at `path` after every [`lookup`](Self::lookup), creating it if
+    /// needed. The file is rotated to `<path>.1` once it grows past
+    /// [`with_max_audit_log_bytes`](Self::with_max_audit_log_bytes).
+    #[cfg(feature = "tokio-io")]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn with_audit_log(mut self, path: PathBuf) -> Result<Self> {
+        self.audit_log = Some(Arc::new(AuditLog::open(path).await?));
+        Ok(self)
+    }
+
+    /// Size in bytes past which the audit log is rotated; 64 MiB by default.
+    #[cfg(feature = "tokio-io")]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn with_max_audit_log_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_audit_log_bytes = max_bytes;
+        self
+    }
+
+    /// Subscribes to an event per successful reader swap. Subscribe before
+    /// any reload is triggered; receivers only see events sent after they
+    /// were created.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn watch_updates(&self) -> broadcast::Receiver<DatabaseUpdatedEvent> {
+        self.updates.subscribe()
+    }
+
+    /// Watches the database's answer for `ip`. The receiver starts with the
+    /// point from the current reader and sees a new value whenever a reload
+    /// such as [`swap_reader`](Self::swap_reader) changes it. Answers come
+    /// straight from the reader, so cached points and overrides are not
+    /// reflected. Subscriptions are shared per IP and dropped at the next
+    /// reload once every receiver is gone.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn subscribe(&self, ip: String) -> watch::Receiver<Option<GeoPoint>> {
+        if let Some(sender) = self.subscriptions.read().get(&ip) {
+            return sender.subscribe();
+        }
+        let mut subscriptions = self.subscriptions.write();
+        let initial = self.resolve_uncached(&ip);
+        subscriptions
+            .entry(ip)
+            .or_insert_with(|| watch::channel(initial).0)
+            .subscribe()
+    }
+
+    fn resolve_uncached(&self, ip: &str) -> Option<GeoPoint> {
+        let reader = self.current_reader()?;
+        self.resolve(&reader, ip.parse().ok()?)
+    }
+
+    /// Re-resolves every subscribed IP against the freshly installed reader
+    /// and notifies receivers whose point changed.
+    fn refresh_subscriptions(&self) {
+        let mut subscriptions = self.subscriptions.write();
+        subscriptions.retain(|_, sender| !sender.is_closed());
+        let mut changed = 0usize;
+        for (ip, sender) in subscriptions.iter() {
+            let point = self.resolve_uncached(ip);
+            let modified = sender.send_if_modified(|current| {
+                if *current == point {
+                    return false;
+                }
+                *current = point;
+                true
+            });
+            changed += usize::from(modified);
+        }
+        debug!(
+            subscriptions = subscriptions.len(),
+            changed, "refreshed geolocation subscriptions"
+        );
+    }
+
+    async fn notify_reader_refreshed(&self, new_node_count: u32) {
+        let evicted = self.evict_nulls().await;
+        info!(
+            evicted,
+            "dropped cached geolocation misses after database reload"
+        );
+        self.refresh_subscriptions();
+        if let Some(callback) = self.reader_refresh_callback.as_ref() {
+            callback();
+        }
+        // No subscribers is not an error.
+        let _ = self.updates.send(DatabaseUpdatedEvent {
+            reload_time: SystemTime::now(),
+            new_node_count,
+        });
+    }
+
+    pub(crate) async fn lookup(&self, ip: &str) -> Option<GeoPoint> {
+        self.lookup_with_metadata(ip).await.0
+    }
+
+    /// Like [`lookup`](Self::lookup), but answers from `tenant_id`'s
+    /// overrides first; other tenants and the shared cache never see them.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_for_tenant(&self, tenant_id: &str, ip: &str) -> Option<GeoPoint> {
+        let overridden = self
+            .tenant_overrides
+            .read()
+            .get(tenant_id)
+            .and_then(|overrides| overrides.get(ip))
+            .cloned();
+        match overridden {
+            Some(point) => Some(point),
+            None => self.lookup(ip).await,
+        }
+    }
+
+    /// Pins `ip` to `point` for [`lookup_for_tenant`](Self::lookup_for_tenant)
+    /// calls with `tenant_id`, replacing any earlier override for that pair.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn set_tenant_override(&self, tenant_id: &str, ip: &str, point: GeoPoint) {
+        self.tenant_overrides
+            .write()
+            .entry(tenant_id.to_string())
+            .or_default()
+            .insert(ip.to_string(), point);
+    }
+
+    /// Like [`lookup`](Self::lookup), but an uncached lookup first takes a
+    /// token from `rate_limiter` and resolves to `None` straight away when
+    /// none is available. Cache hits never consume tokens.
+    #[cfg(feature = "governor")]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn rate_limited_lookup(
+        &self,
+        ip: &str,
+        rate_limiter: &governor::DefaultDirectRateLimiter,
+    ) -> Option<GeoPoint> {
+        let cached = self.cache.read().contains_key(ip);
+        if !cached && rate_limiter.check().is_err() {
+            return None;
+        }
+        self.lookup(ip).await
+    }
+
+    /// Resolves the client IP of `req` from `X-Real-IP`, the first
+    /// `X-Forwarded-For` hop, or the connection's peer address, and stores
+    /// the resulting [`GeoPoint`] in the request extensions when found.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn tag_request<B>(&self, req: &mut axum::http::Request<B>) {
+        let header_ip = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.split(',').next())
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        let ip = header_ip("x-real-ip")
+            .or_else(|| header_ip("x-forwarded-for"))
+            .or_else(|| {
+                req.extensions()
+                    .get::<axum::extract::ConnectInfo<SocketAddr>>()
+                    .map(|info| info.0.ip().to_canonical().to_string())
+            });
+        let Some(ip) = ip else {
+            return;
+        };
+        if let Some(point) = self.lookup(&ip).await {
+            req.extensions_mut().insert(point);
+        }
+    }
+
+    /// Looks up the peer address of an accepted connection. IPv4-mapped IPv6
+    /// peers (from dual-stack listeners) are looked up as plain IPv4 so they
+    /// share cache entries.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_from_socket_addr(&self, addr: SocketAddr) -> Option<GeoPoint> {
+        self.lookup(&addr.ip().to_canonical().to_string()).await
+    }
+
+    /// Looks up the IP that `extract` pulls out of `input`, for sources that
+    /// carry the address somewhere non-standard (a custom header struct, a
+    /// tunnel handshake, ...). The generic form of
+    /// [`lookup_from_socket_addr`](Self::lookup_from_socket_addr).
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_with_extractor<T, F>(
+        &self,
+        input: &T,
+        extract: F,
+    ) -> Option<GeoPoint>
+    where
+        F: Fn(&T) -> String + Send + Sync,
+    {
+        let ip = extract(input);
+        self.lookup(&ip).await
+    }
+
+    /// Like [`lookup`](Self::lookup), but retries up to `retries` times when
+    /// [`swap_reader`](Self::swap_reader) replaced the database while the
+    /// lookup was resolving, so the answer comes from the newest reader. The
+    /// stale answer is dropped from the cache before retrying. Cache hits are
+    /// returned as-is. After the last retry the latest answer is returned
+    /// even if another swap raced it.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_reliable(&self, ip: &str, retries: u8) -> Option<GeoPoint> {
+        let mut attempt = 0;
+        loop {
+            let before = self.current_reader();
+            let (result, source) = self.lookup_with_metadata(ip).await;
+            let after = self.current_reader();
+            let swapped = match (before.as_ref(), after.as_ref()) {
+                (Some(before), Some(after)) => !Arc::ptr_eq(before, after),
+                (None, None) => false,
+                _ => true,
+            };
+            if !swapped || source == GeoSource::Cache || attempt >= retries {
+                return result;
+            }
+            debug!(ip, attempt, "database swapped during lookup; retrying");
+            let stale_ip = ip.to_string();
+            self.with_cache_write(move |cache| cache.remove(&stale_ip))
+                .await;
+            attempt += 1;
+        }
+    }
+
+    /// Like [`lookup`](Self::lookup), but while the circuit breaker from
+    /// [`with_circuit_breaker`](Self::with_circuit_breaker) is open, uncached
+    /// lookups resolve to `None` straight away without touching the reader
+    /// or the cache. After the cooldown one lookup probes the reader; success
+    /// closes the breaker and another error re-opens it. Without a breaker
+    /// this is a plain lookup.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_with_circuit_breaker(&self, ip: &str) -> Option<GeoPoint> {
+        let Some(breaker) = self.circuit_breaker.as_ref() else {
+            return self.lookup(ip).await;
+        };
+        let admission = if self.cache.read().contains_key(ip) {
+            BreakerAdmission::Closed
+        } else {
+            breaker.allow()
+        };
+        if admission == BreakerAdmission::Rejected {
+            debug!(ip, "MaxMind reader circuit breaker open; skipping lookup");
+            return None;
+        }
+        let result = self.lookup(ip).await;
+        if admission == BreakerAdmission::Probe {
+            breaker.release_probe();
+        }
+        result
+    }
+
+    /// Like [`lookup`](Self::lookup), but a `None` caused by a City reader
+    /// error is retried up to `max_retries` times, waiting `base_delay`,
+    /// then twice as long before each further attempt. The failed miss is
+    /// dropped from the cache before retrying. IPs simply absent from the
+    /// database are not retried. Reader errors are counted service-wide, so
+    /// an error on a concurrent lookup can cause an extra, harmless retry.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_with_backoff(
+        &self,
+        ip: &str,
+        max_retries: u8,
+        base_delay: Duration,
+    ) -> Option<GeoPoint> {
+        let span = tracing::debug_span!("geoip_lookup_with_backoff", ip, max_retries);
+        async {
+            let mut delay = base_delay;
+            for attempt in 0..=max_retries {
+                let errors_before = self.reader_errors.load(Ordering::Relaxed);
+                let result = self.lookup(ip).await;
+                let reader_failed = self.reader_errors.load(Ordering::Relaxed) != errors_before;
+                if result.is_some() || !reader_failed || attempt == max_retries {
+                    debug!(
+                        attempts = attempt + 1,
+                        found = result.is_some(),
+                        "geolocation lookup finished"
+                    );
+                    return result;
+                }
+                self.with_cache_write({
+                    let ip = ip.to_string();
+                    move |cache| cache.remove(&ip)
+                })
+                .await;
+                debug!(
+                    attempt = attempt + 1,
+                    delay_ms = delay.as_millis() as u64,
+                    "geolocation reader error; retrying"
+                );
+                tokio::time::sleep(delay).await;
+                delay = delay.saturating_mul(2);
+            }
+            None
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Resolves `host` through DNS and looks up its first address. IP
+    /// literals skip DNS. Resolved addresses are cached for the DNS TTL (see
+    /// [`with_dns_ttl`](Self::with_dns_ttl)), up to
+    /// [`MAX_DNS_CACHE_ENTRIES`] hosts; DNS failures are not cached.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_domain(&self, host: &str) -> Option<GeoPoint> {
+        if host.parse::<IpAddr>().is_ok() {
+            return self.lookup(host).await;
+        }
+        let cached = self
+            .dns_cache
+            .read()
+            .get(host)
+            .filter(|(_, resolved_at)| resolved_at.elapsed() < self.dns_ttl)
+            .map(|(addr, _)| *addr);
+        let addr = match cached {
+            Some(addr) => addr,
+            None => {
+                let addr = match tokio::net::lookup_host((host, 0)).await {
+                    Ok(mut addrs) => addrs.next()?.ip(),
+                    Err(err) => {
+                        debug!(host, ?err, "DNS lookup failed");
+                        return None;
+                    }
+                };
+                remember_resolved_host(&mut self.dns_cache.write(), host, addr, self.dns_ttl);
+                addr
+            }
+        };
+        self.lookup(&addr.to_canonical().to_string()).await
+    }
+
+    /// Like [`lookup`](Self::lookup), paired with a confidence score in
+    /// `(0, 1]`: the inverse of the accuracy radius in km, so a 1 km radius
+    /// scores `1.0` and 100 km scores `0.01`. Points without a radius score
+    /// `1.0`.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_scored(&self, ip: &str) -> Option<(GeoPoint, f64)> {
+        let point = self.lookup(ip).await?;
+        let score = point
+            .accuracy_radius_km
+            .map_or(1.0, |radius| 1.0 / f64::from(radius.max(1)));
+        Some((point, score))
+    }
+
+    /// Resolves `ip` against the City, ASN, Anonymous IP, and Domain
+    /// databases and returns all the answers together. The optional
+    /// databases are plain in-memory reads, so they run one after another
+    /// once the City lookup is done.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_enriched(&self, ip: &str) -> EnrichedGeoPoint {
+        let geo = self.lookup(ip).await;
+        EnrichedGeoPoint {
+            geo,
+            asn: self.lookup_asn(ip),
+            anon: self.lookup_anonymous_ip(ip),
+            registered_domain: self.lookup_registered_domain(ip),
+        }
+    }
+
+    /// Looks up `ip` and compares the answer with the one recorded by the
+    /// previous `lookup_diff` for it, e.g. to notice a peer moving networks
+    /// after a database reload. The record is only replaced when the answer
+    /// changes; the first call for an IP records it and reports no change.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_diff(&self, ip: &str) -> GeoLookupDiff {
+        let current = self.lookup(ip).await;
+        let mut history = self.diff_history.write();
+        let (previous, changed) = match history.get_mut(ip) {
+            Some(recorded) if *recorded != current => {
+                (std::mem::replace(recorded, current.clone()), true)
+            }
+            Some(recorded) => (recorded.clone(), false),
+            None => {
+                history.insert(ip.to_string(), current.clone());
+                (None, false)
+            }
+        };
+        GeoLookupDiff {
+            previous,
+            current,
+            changed,
+        }
+    }
+
+    /// Resolves both IPs concurrently and reports whether they share a
+    /// country or city and how far apart they are.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_compare(&self, ip_a: &str, ip_b: &str) -> GeoCompareResult {
+        let (a, b) = tokio::join!(self.lookup(ip_a), self.lookup(ip_b));
+        let same = |field: fn(&GeoPoint) -> Option<&str>| match (a.as_ref(), b.as_ref()) {
+            (Some(a), Some(b)) => field(a).is_some() && field(a) == field(b),
+            _ => false,
+        };
+        let same_country = same(GeoPoint::country);
+        GeoCompareResult {
+            same_country,
+            same_city: same_country && same(GeoPoint::city),
+            distance_km: a.as_ref().zip(b.as_ref()).map(|(a, b)| a.distance_km(b)),
+            a,
+            b,
+        }
+    }
+
+    /// Like [`lookup`](Self::lookup), but asks `fallback` (e.g. a free
+    /// GeoLite2 service behind a paid primary) when this service finds
+    /// nothing. A fallback hit is cached here so later lookups for the IP
+    /// skip the fallback.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_with_fallback(
+        &self,
+        ip: &str,
+        fallback: &GeoIpService,
+    ) -> Option<GeoPoint> {
+        if let Some(point) = self.lookup(ip).await {
+            return Some(point);
+        }
+        let point = fallback.lookup(ip).await?;
+        self.cache_write(ip, Some(point.clone())).await;
+        Some(point)
+    }
+
+    /// Like [`lookup`](Self::lookup), paired with the point's
+    /// [geohash](GeoPoint::to_geohash) at `precision`. `None` when the IP
+    /// cannot be resolved or the precision is out of range.
+    #[cfg(feature = "geohash")]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_with_geohash(
+        &self,
+        ip: &str,
+        precision: usize,
+    ) -> Option<(GeoPoint, String)> {
+        let point = self.lookup(ip).await?;
+        let hash = point.to_geohash(precision)?;
+        Some((point, hash))
+    }
+
+    /// Like [`lookup`](Self::lookup), but also reports whether the answer was
+    /// served from the cache or resolved just now.
+    pub(crate) async fn lookup_with_metadata(&self, ip: &str) -> (Option<GeoPoint>, GeoSource) {
+        let (result, source) = self.resolve_with_metadata(ip).await;
+        #[cfg(feature = "tokio-io")]
+        if let Some(audit_log) = self.audit_log.as_ref() {
+            let mut line = serde_json::json!({
+                "ts": crate::util::format_rfc3339_ms(crate::util::now_ms()),
+                "ip": ip,
+                "cache_hit": source == GeoSource::Cache,
+                "found": result.is_some(),
+            })
+            .to_string();
+            line.push('\n');
+            audit_log.append(&line, self.max_audit_log_bytes).await;
+        }
+        (result, source)
+    }
+
+    async fn resolve_with_metadata(&self, ip: &str) -> (Option<GeoPoint>, GeoSource) {
+        self.total_lookups.fetch_add(1, Ordering::Relaxed);
+        if ip.is_empty() {
+            return (None, GeoSource::NotFound);
+        }
+
+        if let Some(result) = self.cache.read().get(ip) {
+            self.total_cache_hits.fetch_add(1, Ordering::Relaxed);
+            return (result.clone(), GeoSource::Cache);
+        }
+
+        let result = match self.lookup_timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, self.lookup_uncached(ip)).await {
+                Ok(result) => result,
+                Err(_) => {
+                    warn!(
+                        ip,
+                        timeout_ms = timeout.as_millis() as u64,
+                        "geolocation lookup timed out"
+                    );
+                    return (None, GeoSource::TimedOut);
+                }
+            },
+            None => self.lookup_uncached(ip).await,
+        };
+        let source = if result.is_some() {
+            GeoSource::Live
+        } else {
+            GeoSource::NotFound
+        };
+        (result, source)
+    }
+
+    /// Push-based annotation: items sent into the returned sink come out of
+    /// the stream, in order, paired with the lookup result for their IP. The
+    /// sink applies backpressure once `buffer` items are waiting.
+    #[cfg(feature = "sink")]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn annotate_sink<T: Send + 'static>(
+        &self,
+        buffer: usize,
+    ) -> (
+        futures_channel::mpsc::Sender<IpWithPayload<T>>,
+        impl futures_util::Stream<Item = (Option<GeoPoint>, T)>,
+    ) {
+        use futures_util::StreamExt;
+
+        let (tx, rx) = futures_channel::mpsc::channel(buffer);
+        let service = self.clone();
+        let annotated = rx.then(move |(ip, payload): IpWithPayload<T>| {
+            let service = service.clone();
+            async move { (service.lookup(&ip).await, payload) }
+        });
+        (tx, annotated)
+    }
+
+    /// Admission control for incoming connections: passes through the items
+    /// of `stream` whose IP, as returned by `extract_ip`, does not resolve to
+    /// a country from [`with_blocked_countries`](Self::with_blocked_countries).
+    /// Items whose IP cannot be located are let through.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn filter_stream<S, T, F>(
+        &self,
+        stream: S,
+        extract_ip: F,
+    ) -> impl futures_util::Stream<Item = T>
+    where
+        S: futures_util::Stream<Item = T>,
+        F: Fn(&T) -> &str,
+    {
+        use futures_util::StreamExt;
+
+        let service = self.clone();
+        stream.filter(move |item| {
+            let ip = extract_ip(item).to_string();
+            let service = service.clone();
+            async move {
+                let country = service
+                    .lookup(&ip)
+                    .await
+                    .and_then(|point| point.country)
+                    .map(|country| country.to_lowercase());
+                !country.is_some_and(|country| service.blocked_countries.contains(&country))
+            }
+        })
+    }
+
+    /// Resolves `ips` with at most `max_parallel` lookups in flight, returning
+    /// results in input order.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn parallel_lookup(
+        &self,
+        ips: &[&str],
+        max_parallel: usize,
+    ) -> Vec<Option<GeoPoint>> {
+        use futures_util::StreamExt;
+
+        let mut indexed: Vec<(usize, Option<GeoPoint>)> =
+            futures_util::stream::iter(ips.iter().enumerate())
+                .map(|(index, ip)| async move { (index, self.lookup(ip).await) })
+                .buffer_unordered(max_parallel.max(1))
+                .collect()
+                .await;
+        indexed.sort_unstable_by_key(|(index, _)| *index);
+        indexed.into_iter().map(|(_, point)| point).collect()
+    }
+
+    /// Groups `ips` by region, e.g. for geo-aware shard assignment. Every IP
+    /// appears exactly once, in input order within its region; IPs without
+    /// the requested data are grouped under [`UNKNOWN_REGION`].
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn build_routing_table(
+        &self,
+        ips: &[&str],
+        group_by: GroupBy,
+    ) -> HashMap<String, Vec<String>> {
+        let mut table: HashMap<String, Vec<String>> = HashMap::new();
+        for ip in ips {
+            let point = self.lookup(ip).await;
+            table
+                .entry(group_by.region(point.as_ref()))
+                .or_default()
+                .push(ip.to_string());
+        }
+        table
+    }
+
+    /// Ranks leader candidates for `client_ip`: each base score is reduced by
+    /// the [`estimated_latency_ms`](GeoPoint::estimated_latency_ms) (default
+    /// [`LatencyModel`]) between the client and the candidate, and the result
+    /// is sorted best first. Candidates that cannot be located take the
+    /// penalty for the farthest possible point; if the client cannot be
+    /// located, base scores are used unchanged.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn score_candidates<'a>(
+        &self,
+        client_ip: &str,
+        candidates: &[(&'a str, f64)],
+    ) -> Vec<(&'a str, f64)> {
+        let model = LatencyModel::default();
+        let client = self.lookup(client_ip).await;
+        let mut scored = Vec::with_capacity(candidates.len());
+        for &(ip, base) in candidates {
+            let penalty = match client.as_ref() {
+                Some(client) => match self.lookup(ip).await {
+                    Some(point) => client.estimated_latency_ms(&point, model),
+                    None => {
+                        model.base_ms + std::f64::consts::PI * EARTH_RADIUS_KM * model.ms_per_km
+                    }
+                },
+                None => 0.0,
+            };
+            scored.push((ip, base - penalty));
+        }
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored
+    }
+
+    /// The candidate closest to `client_ip` by great-circle distance, ties
+    /// going to the lexicographically smallest IP so every caller picks the
+    /// same one. `None` when there are no candidates, none can be located,
+    /// or the client cannot be located.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn select_leader<'a>(
+        &self,
+        candidate_ips: &[&'a str],
+        client_ip: &str,
+    ) -> Option<&'a str> {
+        if candidate_ips.is_empty() {
+            return None;
+        }
+        let client = self.lookup(client_ip).await?;
+        let mut best: Option<(&'a str, f64)> = None;
+        for &ip in candidate_ips {
+            let Some(point) = self.lookup(ip).await else {
+                continue;
+            };
+            let distance = client.distance_km(&point);
+            let closer = match best {
+                None => true,
+                Some((best_ip, best_distance)) => distance
+                    .total_cmp(&best_distance)
+                    .then_with(|| ip.cmp(best_ip))
+                    .is_lt(),
+            };
+            if closer {
+                best = Some((ip, distance));
+            }
+        }
+        best.map(|(ip, _)| ip)
+    }
+
+    /// Resolves every address from `start` to `end` inclusive, stopping after
+    /// [`MAX_LOOKUP_RANGE`] addresses. Both ends must be the same IP family.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_range(
+        &self,
+        start: IpAddr,
+        end: IpAddr,
+    ) -> Result<Vec<(IpAddr, Option<GeoPoint>)>> {
+        let (first, last, v4) = match (start, end) {
+            (IpAddr::V4(start), IpAddr::V4(end)) => {
+                (u32::from(start) as u128, u32::from(end) as u128, true)
+            }
+            (IpAddr::V6(start), IpAddr::V6(end)) => (u128::from(start), u128::from(end), false),
+            _ => {
+                return Err(anyhow!(
+                    "lookup range {} - {} mixes IPv4 and IPv6",
+                    start,
+                    end
+                ))
+            }
+        };
+        if first > last {
+            return Err(anyhow!("lookup range start {} is after end {}", start, end));
+        }
+        let count = (last - first).min(MAX_LOOKUP_RANGE as u128 - 1) + 1;
+        let mut results = Vec::with_capacity(count as usize);
+        for offset in 0..count {
+            let addr = if v4 {
+                IpAddr::V4(std::net::Ipv4Addr::from((first + offset) as u32))
+            } else {
+                IpAddr::V6(std::net::Ipv6Addr::from(first + offset))
+            };
+            results.push((addr, self.lookup(&addr.to_string()).await));
+        }
+        Ok(results)
+    }
+
+    /// Every cached point as a JSON object keyed by IP, for embedding next to
+    /// a roster in a REST response. Cached misses are left out. At most
+    /// [`MAX_JSON_MAP_ENTRIES`] entries are included, taken in IP string
+    /// order so the truncation is stable between calls.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn as_json_map(&self) -> serde_json::Value {
+        let mut entries = self.lookup_all_cached().await;
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+            .into_iter()
+            .take(MAX_JSON_MAP_ENTRIES)
+            .filter_map(|(ip, point)| Some((ip, serde_json::to_value(point).ok()?)))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+
+    /// Looks up `ip` as a JSON object of the [`GeoPoint`] fields plus `ip`,
+    /// or `null` when it cannot be resolved.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_json(&self, ip: &str) -> serde_json::Value {
+        let Some(point) = self.lookup(ip).await else {
+            return serde_json::Value::Null;
+        };
+        let mut value = serde_json::to_value(point).unwrap_or(serde_json::Value::Null);
+        if let Some(object) = value.as_object_mut() {
+            object.insert("ip".to_string(), ip.into());
+        }
+        value
+    }
+
+    /// Like [`lookup`](Self::lookup), and publishes a [`GeoEvent`] on the
+    /// channel from [`with_event_channel`](Self::with_event_channel) when the
+    /// point was freshly resolved. Cache hits and misses publish nothing.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_and_publish(&self, ip: &str) -> Option<GeoPoint> {
+        let (result, source) = self.lookup_with_metadata(ip).await;
+        if let (GeoSource::Live, Some(point), Some(events)) =
+            (source, result.as_ref(), self.events.as_ref())
+        {
+            // No subscribers is not an error.
+            let _ = events.send(GeoEvent {
+                ip: ip.to_string(),
+                point: point.clone(),
+            });
+        }
+        result
+    }
+
+    /// Like [`lookup`](Self::lookup), and emits a debug event describing the
+    /// result for audit-style logging.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_and_log(&self, ip: &str) -> Option<GeoPoint> {
+        let (result, source) = self.lookup_with_metadata(ip).await;
+        let point = result.as_ref();
+        debug!(
+            ip,
+            city = point.and_then(|p| p.city.as_deref()),
+            country = point.and_then(|p| p.country.as_deref()),
+            lat = point.map(|p| p.latitude),
+            lon = point.map(|p| p.longitude),
+            cache_hit = source == GeoSource::Cache,
+            "geolocation lookup"
+        );
+        result
+    }
+
+    /// Performs a [`lookup`](Self::lookup) and describes each step on its own
+    /// line (parse, cache, reader, database result, point), like
+    /// `curl --verbose` for geolocation. The database result says when the
+    /// reader was never asked (bad IP, no reader) or the lookup timed out.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_debug(&self, ip: &str) -> String {
+        let parsed = ip.parse::<IpAddr>();
+        let mut lines = vec![match &parsed {
+            Ok(_) => "Parse: OK".to_string(),
+            Err(err) => format!("Parse: FAILED ({err})"),
+        }];
+        let (result, source) = self.lookup_with_metadata(ip).await;
+        lines.push(match source {
+            GeoSource::Cache => "Cache: HIT".to_string(),
+            _ => "Cache: MISS".to_string(),
+        });
+        if source != GeoSource::Cache {
+            let has_reader = self.current_reader().is_some();
+            lines.push(format!(
+                "Reader: {}",
+                if has_reader { "present" } else { "absent" }
+            ));
+            let outcome = if parsed.is_err() {
+                "skipped (parse failed)"
+            } else if source == GeoSource::TimedOut {
+                "timed out"
+            } else if result.is_some() {
+                "found"
+            } else if !has_reader {
+                "skipped (no reader)"
+            } else {
+                "not found"
+            };
+            lines.push(format!("MaxMind result: {outcome}"));
+        }
+        lines.push(match result.as_ref() {
+            Some(point) => format!(
+                "GeoPoint: (lat={}, lon={}, city={}, country={})",
+                point.latitude,
+                point.longitude,
+                point.city.as_deref().unwrap_or("-"),
+                point.country.as_deref().unwrap_or("-")
+            ),
+            None => "GeoPoint: none".to_string(),
+        });
+        lines.join("\n")
+    }
+
+    /// Like [`lookup`](Self::lookup), and appends a JSON-lines audit record
+    /// (`ts`, `ip`, `lat`, `lon`) to `log`. Unresolved IPs are recorded with
+    /// null coordinates; write failures are logged and do not affect the
+    /// returned point.
+    #[cfg(feature = "tokio-io")]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_and_record(
+        &self,
+        ip: &str,
+        log: &mut (impl tokio::io::AsyncWrite + Unpin),
+    ) -> Option<GeoPoint> {
+        use tokio::io::AsyncWriteExt;
+
+        let result = self.lookup(ip).await;
+        let mut line = serde_json::json!({
+            "ts": crate::util::format_rfc3339_ms(crate::util::now_ms()),
+            "ip": ip,
+            "lat": result.as_ref().map(|point| point.latitude),
+            "lon": result.as_ref().map(|point| point.longitude),
+        })
+        .to_string();
+        line.push('\n');
+        if let Err(err) = log.write_all(line.as_bytes()).await {
+            warn!(?err, "failed to append geolocation audit record");
+        }
+        result
+    }
+
+    /// Streams `(ip, point)` for every line of the access log at `path`,
+    /// taking the first whitespace-separated token as the client IP (Common
+    /// and Combined Log Format). Lines are read lazily, so memory use does
+    /// not grow with the file; repeated IPs are answered by the cache. Blank
+    /// lines are skipped and a read error ends the stream.
+    #[cfg(feature = "tokio-io")]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_all_from_log_file(
+        &self,
+        path: &Path,
+    ) -> Result<impl futures_util::Stream<Item = (String, Option<GeoPoint>)>> {
+        use tokio::io::AsyncBufReadExt;
+
+        let file = tokio::fs::File::open(path)
+            .await
+            .with_context(|| format!("failed to open access log {}", path.display()))?;
+        let mut lines = tokio::io::BufReader::new(file).lines();
+        let service = self.clone();
+        Ok(async_stream::stream! {
+            loop {
+                let line = match lines.next_line().await {
+                    Ok(Some(line)) => line,
+                    Ok(None) => break,
+                    Err(err) => {
+                        warn!(?err, "failed to read access log; stopping");
+                        break;
+                    }
+                };
+                let Some(ip) = line.split_whitespace().next() else {
+                    continue;
+                };
+                let ip = ip.to_string();
+                let point = service.lookup(&ip).await;
+                yield (ip, point);
+            }
+        })
+    }
+
+    /// Serves this service's lookups to other processes on the host over a
+    /// Unix socket at `path`, replacing a stale socket file left by a previous
+    /// run. Each request frame is an IP and each response frame the JSON
+    /// `Option<GeoPoint>`; frames are a big-endian `u32` length followed by
+    /// the payload. Runs, connections included, until the returned task is
+    /// aborted.
+    #[cfg(feature = "ipc")]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn bind_cache_socket(&self, path: &Path) -> Result<tokio::task::JoinHandle<()>> {
+        use std::os::unix::fs::FileTypeExt;
+
+        if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            fs::remove_file(path).with_context(|| {
+                format!("failed to remove stale cache socket {}", path.display())
+            })?;
+        }
+        let listener = tokio::net::UnixListener::bind(path)
+            .with_context(|| format!("failed to bind cache socket {}", path.display()))?;
+        info!(path = %path.display(), "serving geolocation cache over Unix socket");
+        let service = self.clone();
+        Ok(tokio::spawn(async move {
+            // Owned by this task so aborting it also closes every connection.
+            let mut connections = tokio::task::JoinSet::new();
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => {
+                            let service = service.clone();
+                            connections.spawn(async move {
+                                if let Err(err) = service.serve_cache_connection(stream).await {
+                                    debug!(?err, "cache socket connection closed");
+                                }
+                            });
+                        }
+                        Err(err) => warn!(?err, "failed to accept cache socket connection"),
+                    },
+                    Some(_) = connections.join_next(), if !connections.is_empty() => {}
+                }
+            }
+        }))
+    }
+
+    #[cfg(feature = "ipc")]
+    async fn serve_cache_connection(
+        &self,
+        mut stream: tokio::net::UnixStream,
+    ) -> std::io::Result<()> {
+        while let Some(request) = read_ipc_frame(&mut stream).await? {
+            let ip = String::from_utf8_lossy(&request);
+            let response = serde_json::to_vec(&self.lookup(&ip).await)?;
+            write_ipc_frame(&mut stream, &response).await?;
+        }
+        Ok(())
+    }
+
+    /// Forwards uncached lookups to the process serving
+    /// [`bind_cache_socket`](Self::bind_cache_socket) at `path` instead of
+    /// reading the local database. Answers are cached locally too. If the
+    /// socket fails, the connection is dropped and lookups fall back to the
+    /// local reader until this is called again.
+    #[cfg(feature = "ipc")]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn connect_cache_socket(&self, path: &Path) -> Result<()> {
+        let stream = tokio::net::UnixStream::connect(path)
+            .await
+            .with_context(|| format!("failed to connect to cache socket {}", path.display()))?;
+        *self.cache_socket.lock().await = Some(stream);
+        Ok(())
+    }
+
+    /// `None` when no cache socket is connected or the request failed.
+    #[cfg(feature = "ipc")]
+    async fn remote_lookup(&self, ip: &str) -> Option<Option<GeoPoint>> {
+        let mut slot = self.cache_socket.lock().await;
+        // Taken out while in flight so a lookup cancelled mid-request (e.g. by
+        // the lookup timeout) drops the half-used stream instead of leaving a
+        // stale response for the next caller.
+        let mut stream = slot.take()?;
+        let result = async {
+            write_ipc_frame(&mut stream, ip.as_bytes()).await?;
+            let response = read_ipc_frame(&mut stream)
+                .await?
+                .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+            Ok::<_, std::io::Error>(serde_json::from_slice::<Option<GeoPoint>>(&response)?)
+        }
+        .await;
+        match result {
+            Ok(point) => {
+                *slot = Some(stream);
+                Some(point)
+            }
+            Err(err) => {
+                warn!(?err, "cache socket request failed; resolving locally");
+                None
+            }
+        }
+    }
+
+    /// Like [`lookup`](Self::lookup), but runs inside a `geo_lookup` span
+    /// parented to `parent` so traces nest under the calling request.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_with_context(&self, ip: &str, parent: &Span) -> Option<GeoPoint> {
+        let span = info_span!(parent: parent.clone(), "geo_lookup", ip = %ip);
+        self.lookup(ip).instrument(span).await
+    }
+
+    /// Like [`lookup`](Self::lookup), and records the result on `span` as
+    /// `geo.country`, `geo.city`, `geo.lat`, and `geo.lon` (OpenTelemetry
+    /// naming). `tracing` only records fields declared when the span was
+    /// created, so declare them as `tracing::field::Empty`; absent values are
+    /// left unrecorded.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn tag_span(&self, ip: &str, span: &Span) -> Option<GeoPoint> {
+        let point = self.lookup(ip).await?;
+        span.record("geo.country", point.country.as_deref());
+        span.record("geo.city", point.city.as_deref());
+        span.record("geo.lat", point.latitude);
+        span.record("geo.lon", point.longitude);
+        Some(point)
+    }
+
+    /// Like [`lookup`](Self::lookup), wrapped in a `geo_lookup` span started
+    /// on `tracer` that carries `net.peer.ip` and, when resolved,
+    /// `geo.country.iso_code` and `geo.city.name` (OpenTelemetry semantic
+    /// conventions). The span is ended before this returns.
+    #[cfg(feature = "opentelemetry")]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup_with_otel<T>(&self, ip: &str, tracer: &T) -> Option<GeoPoint>
+    where
+        T: opentelemetry::trace::Tracer,
+    {
+        use opentelemetry::trace::Span as _;
+        use opentelemetry::KeyValue;
+
+        let mut span = tracer.start("geo_lookup");
+        span.set_attribute(KeyValue::new("net.peer.ip", ip.to_string()));
+        let point = self.lookup(ip).await;
+        if let Some(point) = point.as_ref() {
+            if let Some(iso_code) = point.country_iso_code.clone() {
+                span.set_attribute(KeyValue::new("geo.country.iso_code", iso_code));
+            }
+            if let Some(city) = point.city.clone() {
+                span.set_attribute(KeyValue::new("geo.city.name", city));
+            }
+        }
+        span.end();
+        point
+    }
+
+    async fn lookup_uncached(&self, ip: &str) -> Option<GeoPoint> {
+        let ip_addr: IpAddr = match ip.parse() {
+            Ok(addr) => addr,
+            Err(_) => {
+                self.cache_write(ip, None).await;
+                return None;
+            }
+        };
+
+        #[cfg(feature = "ipc")]
+        if let Some(result) = self.remote_lookup(ip).await {
+            self.cache_write(ip, result.clone()).await;
+            return result;
+        }
+
+        self.init_lazy_reader().await;
+        let reader = match self.current_reader() {
+            Some(reader) => reader,
+            None => {
+                self.cache_write(ip, None).await;
+                return None;
+            }
+        };
+
+        // Held until the result is cached, so the limit covers the reader
+        // task and the cache write rather than just spawning them.
+        let _permit = match self.lookup_limit.as_ref() {
+            Some(limit) => limit.acquire().await.ok(),
+            None => None,
+        };
+        let service = self.clone();
+        // Carry the caller's span and subscriber onto the blocking thread so
+        // events from `resolve`, such as not-found tracing, stay in context.
+        let span = Span::current();
+        let dispatch = tracing::dispatcher::get_default(Clone::clone);
+        let result = tokio::task::spawn_blocking(move || {
+            tracing::dispatcher::with_default(&dispatch, || {
+                span.in_scope(|| service.resolve(&reader, ip_addr))
+            })
+        })
+        .await
+        .unwrap_or_else(|err| {
+            warn!(?err, "geolocation lookup task failed");
+            None
+        });
+        self.cache_write(ip, result.clone()).await;
+        result
+    }
+
+    /// Waits for a [`new_lazy`](Self::new_lazy) service's City database,
+    /// starting the load if none is running.
+    async fn init_lazy_reader(&self) {
+        let Some(lazy) = self.lazy_reader.as_ref() else {
+            return;
+        };
+        if self.current_reader().is_some() {
+            return;
+        }
+        // Registered before the checks below so a load finishing in between
+        // still wakes this lookup.
+        let mut loaded = std::pin::pin!(lazy.loaded.notified());
+        loaded.as_mut().enable();
+        if !lazy.loading.swap(true, Ordering::AcqRel) {
+            tokio::spawn(self.clone().load_lazy_reader(Arc::clone(lazy)));
+        }
+        if self.current_reader().is_none() {
+            loaded.await;
+        }
+    }
+
+    async fn load_lazy_reader(self, lazy: Arc<LazyReader>) {
+        let started = Instant::now();
+        let result = load_geoip(&lazy.config).await.and_then(|service| {
+            service
+                .current_reader()
+                .ok_or_else(|| anyhow!("MaxMind database is not a City database"))
+        });
+        match result {
+            Ok(reader) => {
+                let node_count = reader.metadata.node_count;
+                self.install_reader(reader);
+                info!(
+                    elapsed_ms = started.elapsed().as_millis() as u64,
+                    "lazily loaded MaxMind database"
+                );
+                // Drops misses cached while no reader was loaded, e.g. by
+                // lookups after an earlier failed load.
+                self.notify_reader_refreshed(node_count).await;
+            }
+            Err(err) => {
+                warn!(?err, "failed to lazily load MaxMind database");
+            }
+        }
+        lazy.loading.store(false, Ordering::Release);
+        lazy.loaded.notify_waiters();
+    }
+
+    /// Resolves `ip` without Tokio: a cached answer is returned if the cache
+    /// is not being written, otherwise the database is read on `executor`
+    /// and the result cached.
+    #[cfg(feature = "rayon")]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn lookup_blocking(
+        &self,
+        ip: &str,
+        executor: &rayon::ThreadPool,
+    ) -> Option<GeoPoint> {
+        if let Some(cache) = self.cache.try_read() {
+            if let Some(result) = cache.get(ip) {
+                return result.clone();
+            }
+        }
+        let result = executor.install(|| {
+            let ip_addr = ip.parse::<IpAddr>().ok()?;
+            let reader = self.current_reader()?;
+            self.resolve(&reader, ip_addr)
+        });
+        self.cache.write().insert(ip.to_string(), result.clone());
+        result
+    }
+
+    fn resolve(&self, reader: &Reader<Vec<u8>>, ip_addr: IpAddr) -> Option<GeoPoint> {
+        let mut lookup_addr = ip_addr;
+        let mut result = self.read_point(reader, lookup_addr);
+        if result.is_none() {
+            // City databases only carry IPv4 data in the IPv4 subtree, so an
+            // IPv4-mapped IPv6 address (::ffff:a.b.c.d) needs a second lookup.
+            if let IpAddr::V6(v6) = ip_addr {
+                if let Some(v4) = v6.to_ipv4_mapped() {
+                    lookup_addr = IpAddr::V4(v4);
+                    result = self.read_point(reader, lookup_addr);
+                }
+            }
+        }
+        if let (Some(point), Some(anon_reader)) = (result.as_mut(), self.anon_reader.as_ref()) {
+            self.merge_anonymous_ip(anon_reader, lookup_addr, point);
+        }
+        if let (None, Some(counts)) = (result.as_ref(), self.not_found_counts.as_ref()) {
+            let misses = {
+                let mut counts = counts.lock();
+                let misses = counts.entry(ip_addr).or_insert(0);
+                *misses += 1;
+                *misses
+            };
+            debug!(ip = %ip_addr, misses, "IP not found in MaxMind database");
+        }
+        result
+    }
+
+    fn read_point(&self, reader: &Reader<Vec<u8>>, ip_addr: IpAddr) -> Option<GeoPoint> {
+        let result = reader
+            .lookup(ip_addr)
+            .and_then(|lookup| lookup.decode::<City>());
+        if let Some(breaker) = self.circuit_breaker.as_ref() {
+            match result {
+                Ok(_) => breaker.record_success(),
+                Err(_) => breaker.record_failure(),
+            }
+        }
+        match result {
+            Ok(city) => extract_point(city.as_ref()?, &self.timezone_fallback),
+            Err(err) => {
+                self.reader_errors.fetch_add(1, Ordering::Relaxed);
+                self.log_lookup_error_once(err);
+                None
+            }
+        }
+    }
+
+    /// Reads the Anonymous IP record for `ip` from the Anonymous IP database.
+    /// Returns `None` when no such database is loaded or `ip` is invalid.
+    pub(crate) fn lookup_anonymous_ip(&self, ip: &str) -> Option<AnonymousIpInfo> {
+        let reader = self.anon_reader.as_ref()?;
+        self.read_anonymous_ip(reader, parse_unmapped_ip(ip)?)
+    }
+
+    /// Reads the ASN record for `ip` from the ASN database. Returns `None`
+    /// when no such database is loaded, `ip` is invalid, or it has no record.
+    pub(crate) fn lookup_asn(&self, ip: &str) -> Option<AsnInfo> {
+        let reader = self.asn_reader.as_ref()?;
+        let record = match reader.lookup(parse_unmapped_ip(ip)?) {
+            Ok(lookup) => match lookup.decode::<Asn>() {
+                Ok(record) => record?,
+                Err(err) => {
+                    self.log_lookup_error_once(err);
+                    return None;
+                }
+            },
+            Err(err) => {
+                self.log_lookup_error_once(err);
+                return None;
+            }
+        };
+        Some(AsnInfo {
+            number: record.autonomous_system_number?,
+            organization: record
+                .autonomous_system_organization
+                .map(|value| value.to_string()),
+        })
+    }
+
+    /// Reads the second-level domain registered for `ip`'s network from the
+    /// Domain database, e.g. `example.com`. This comes from MaxMind's
+    /// registration data, not the IP's PTR record, so it names the network
+    /// owner rather than any hostname a reverse DNS lookup would return.
+    /// Returns `None` when no Domain database is loaded or it has no record.
+    pub(crate) fn lookup_registered_domain(&self, ip: &str) -> Option<String> {
+        let reader = self.domain_reader.as_ref()?;
+        match reader.lookup(parse_unmapped_ip(ip)?) {
+            Ok(lookup) => match lookup.decode::<Domain>() {
+                Ok(record) => record?.domain.map(|value| value.to_string()),
+                Err(err) => {
+                    self.log_lookup_error_once(err);
+                    None
+                }
+            },
+            Err(err) => {
+                self.log_lookup_error_once(err);
+                None
+            }
+        }
+    }
+
+    fn read_anonymous_ip(
+        &self,
+        reader: &Reader<Vec<u8>>,
+        ip_addr: IpAddr,
+    ) -> Option<AnonymousIpInfo> {
+        match reader.lookup(ip_addr) {
+            Ok(lookup) => match lookup.decode::<AnonymousIp>() {
+                Ok(record) => Some(
+                    record
+                        .as_ref()
+                        .map(AnonymousIpInfo::from_record)
+                        .unwrap_or_default(),
+                ),
+                Err(err) => {
+                    self.log_lookup_error_once(err);
+                    None
+                }
+            },
+            Err(err) => {
+                self.log_lookup_error_once(err);
+                None
+            }
+        }
+    }
+
+    fn merge_anonymous_ip(&self, reader: &Reader<Vec<u8>>, ip_addr: IpAddr, point: &mut GeoPoint) {
+        if let Some(info) = self.read_anonymous_ip(reader, ip_addr) {
+            point.is_anonymous = Some(info.is_anonymous);
+            point.is_vpn = Some(info.is_vpn);
+            point.is_tor = Some(info.is_tor);
+        }
+    }
+
+    /// Spawns a task that logs cache size, miss rate, and lookup totals every
+    /// `interval`, for deployments without a metrics pipeline.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn start_stats_logging(
+        service: Arc<GeoIpService>,
+        interval: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            loop {
+                ticker.tick().await;
+                let total_lookups = service.total_lookup_count();
+                let cache_hits = service.total_cache_hit_count();
+                let cache_miss_rate = if total_lookups == 0 {
+                    0.0
+                } else {
+                    1.0 - cache_hits as f64 / total_lookups as f64
+                };
+                let cache_size = service.cache.read().len();
+                info!(
+                    cache_size,
+                    cache_miss_rate, total_lookups, "geolocation cache stats"
+                );
+            }
+        })
+    }
+
+    /// Whether lookups can currently be answered from a City database: one
+    /// is loaded and the circuit breaker, if any, is not open. A lazy
+    /// service is unhealthy until its first lookup loads the database.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn healthy(&self) -> bool {
+        self.current_reader().is_some()
+            && !self
+                .circuit_breaker
+                .as_ref()
+                .is_some_and(|breaker| breaker.is_open())
+    }
+
+    /// Serves [`healthy`](Self::healthy) over the `grpc.health.v1.Health`
+    /// protocol, e.g. for Kubernetes gRPC liveness probes, as `SERVING` or
+    /// `NOT_SERVING` for the overall (`""`) service. Other service names
+    /// are `NOT_FOUND`. `Watch` streams re-check every few seconds and send
+    /// only changes.
+    #[cfg(feature = "grpc")]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn into_health_service(
+        self,
+    ) -> tonic_health::pb::health_server::HealthServer<GeoIpHealthService> {
+        tonic_health::pb::health_server::HealthServer::new(GeoIpHealthService::new(self))
+    }
+
+    /// Metadata of the City database, or `None` without one.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn database_info(&self) -> Option<DatabaseInfo> {
+        self.current_reader()
+            .as_deref()
+            .map(DatabaseInfo::from_reader)
+    }
+
+    fn current_reader(&self) -> Option<SharedReader> {
+        self.reader.read().clone()
+    }
+
+    fn install_reader(&self, reader: SharedReader) {
+        *self.reader.write() = Some(reader);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.reader_loaded_at
+            .store(now.as_secs(), Ordering::Relaxed);
+    }
+
+    /// Replaces the City database with the one at `path` for every clone of
+    /// this service. The new file must be a City database; on any error the
+    /// current reader stays in place. Cached misses are dropped so IPs the
+    /// new database knows about resolve again, while cached points are kept.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn swap_reader(&self, path: &Path) -> Result<DatabaseInfo> {
+        let owned_path = path.to_path_buf();
+        let reader = tokio::task::spawn_blocking(move || Reader::open_readfile(&owned_path))
+            .await
+            .context("database open task failed")?
+            .with_context(|| format!("failed to open MaxMind database at {}", path.display()))?;
+        if !reader
+            .metadata
+            .database_type
+            .to_lowercase()
+            .contains("city")
+        {
+            return Err(anyhow!(
+                "refusing to swap in {} database from {}; expected a City database",
+                reader.metadata.database_type,
+                path.display()
+            ));
+        }
+        let info = DatabaseInfo::from_reader(&reader);
+        self.install_reader(Arc::new(reader));
+        info!(
+            path = %path.display(),
+            database_type = %info.database_type,
+            build_epoch = info.build_epoch,
+            node_count = info.node_count,
+            "swapped MaxMind database"
+        );
+        self.notify_reader_refreshed(info.node_count).await;
+        Ok(info)
+    }
+
+    /// Cache size, hit and miss counts, and database load time in the
+    /// Prometheus text exposition format, for embedding in an existing
+    /// `/metrics` endpoint.
+    #[cfg(feature = "prometheus")]
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn export_prometheus_text(&self) -> String {
+        use prometheus::{Encoder, IntCounter, IntGauge, Registry, TextEncoder};
+
+        let hits = self.total_cache_hit_count();
+        let misses = self.total_lookup_count().saturating_sub(hits);
+        let cache_entries = self.cache.read().len() as i64;
+        let loaded_at = self.reader_loaded_at.load(Ordering::Relaxed) as i64;
+
+        // Names and help strings are static and valid, so creation and
+        // registration cannot fail.
+        let registry = Registry::new();
+        let gauge = |name: &str, help: &str, value: i64| {
+            let gauge = IntGauge::new(name, help).expect("valid gauge");
+            gauge.set(value);
+            registry
+                .register(Box::new(gauge))
+                .expect("unique metric name");
+        };
+        gauge(
+            "geoip_cache_entries",
+            "Entries in the geolocation cache, including cached misses.",
+            cache_entries,
+        );
+        gauge(
+            "geoip_database_loaded_timestamp_seconds",
+            "Unix time the City database was loaded; 0 before any load.",
+            loaded_at,
+        );
+        let counter = |name: &str, help: &str, value: u64| {
+            let counter = IntCounter::new(name, help).expect("valid counter");
+            counter.inc_by(value);
+            registry
+                .register(Box::new(counter))
+                .expect("unique metric name");
+        };
+        counter(
+            "geoip_cache_hits_total",
+            "Lookups answered from the geolocation cache.",
+            hits,
+        );
+        counter(
+            "geoip_cache_misses_total",
+            "Lookups not answered from the geolocation cache.",
+            misses,
+        );
+
+        let mut buffer = Vec::new();
+        if let Err(err) = TextEncoder::new().encode(&registry.gather(), &mut buffer) {
+            warn!(?err, "failed to encode geolocation metrics");
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+
+    /// Lookups served since this service was created, including cache hits.
+    pub(crate) fn total_lookup_count(&self) -> u64 {
+        self.total_lookups.load(Ordering::Relaxed)
+    }
+
+    /// Lookups answered from the cache since this service was created.
+    pub(crate) fn total_cache_hit_count(&self) -> u64 {
+        self.total_cache_hits.load(Ordering::Relaxed)
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn compute_stats(&self) -> GeoStats {
+        let cache = self.cache.read();
+        let mut countries = HashSet::new();
+        let mut cities = HashSet::new();
+        let mut resolved = 0usize;
+        for point in cache.values().flatten() {
+            resolved += 1;
+            if let Some(country) = point.country.as_deref() {
+                countries.insert(country);
+            }
+            if let Some(city) = point.city.as_deref() {
+                cities.insert((city, point.country.as_deref()));
+            }
+        }
+        let total_cached = cache.len();
+        let coverage_percent = if total_cached == 0 {
+            0.0
+        } else {
+            resolved as f64 / total_cached as f64 * 100.0
+        };
+        GeoStats {
+            unique_countries: countries.len(),
+            unique_cities: cities.len(),
+            total_cached,
+            coverage_percent,
+        }
+    }
 
-impl GeoIpService {
-    pub(crate) fn from_reader(reader: Reader<Vec<u8>>) -> Self {
-        Self {
-            reader: Some(Arc::new(reader)),
-            cache: Arc::new(RwLock::new(HashMap::new())),
-            lookup_error_logged: Arc::new(AtomicBool::new(false)),
+    /// How many cached IPs fall in each region, without new lookups. Cached
+    /// misses and points lacking the field count towards
+    /// [`UNKNOWN_REGION`]; regions with no IPs are absent.
+    pub(crate) async fn count_by_region(&self, region: GroupBy) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for point in self.cache.read().values() {
+            *counts.entry(region.region(point.as_ref())).or_insert(0) += 1;
         }
+        counts
     }
 
-    #[cfg(test)]
-    pub(crate) fn from_static(entries: HashMap<String, Option<GeoPoint>>) -> Self {
-        Self {
-            reader: None,
-            cache: Arc::new(RwLock::new(entries)),
-            lookup_error_logged: Arc::new(AtomicBool::new(false)),
+    /// [`count_by_region`](Self::count_by_region) sorted by descending count,
+    /// ties broken by region name.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn count_by_region_sorted(&self, region: GroupBy) -> Vec<(String, usize)> {
+        let mut counts: Vec<_> = self.count_by_region(region).await.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts
+    }
+
+    /// Counts cached points per cell of an equirectangular `grid_cols` by
+    /// `grid_rows` grid, indexed `[row][col]` with row 0 at the north pole
+    /// and column 0 at the antimeridian, the same orientation as
+    /// [`GeoPoint::to_svg_pin`]. Points on the east or south edge fall in
+    /// the last column or row. Cached misses are skipped; a zero dimension
+    /// yields an empty grid.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn generate_heatmap(
+        &self,
+        grid_cols: usize,
+        grid_rows: usize,
+    ) -> Vec<Vec<u32>> {
+        if grid_cols == 0 || grid_rows == 0 {
+            return Vec::new();
         }
+        let mut grid = vec![vec![0u32; grid_cols]; grid_rows];
+        let cell = |fraction: f64, cells: usize| {
+            ((fraction * cells as f64).floor() as usize).min(cells - 1)
+        };
+        for point in self.cache.read().values().flatten() {
+            let col = cell((point.longitude + 180.0) / 360.0, grid_cols);
+            let row = cell((90.0 - point.latitude) / 180.0, grid_rows);
+            grid[row][col] = grid[row][col].saturating_add(1);
+        }
+        grid
     }
 
-    pub(crate) async fn lookup(&self, ip: &str) -> Option<GeoPoint> {
-        if ip.is_empty() {
-            return None;
+    /// Resolves every IP in `ips` against the current City database and
+    /// reports how many it places. The cache is neither read nor written, so
+    /// the report reflects the database alone, e.g. right after a swap.
+    /// Invalid IPs count as not found.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn coverage_check(&self, ips: &[&str]) -> CoverageReport {
+        let reader = self.current_reader();
+        let found = match reader.as_deref() {
+            Some(reader) => ips
+                .iter()
+                .filter_map(|ip| ip.parse::<IpAddr>().ok())
+                .filter(|ip_addr| self.resolve(reader, *ip_addr).is_some())
+                .count(),
+            None => 0,
+        };
+        let total = ips.len();
+        let coverage_pct = if total == 0 {
+            0.0
+        } else {
+            found as f64 / total as f64 * 100.0
+        };
+        CoverageReport {
+            total,
+            found,
+            not_found: total - found,
+            coverage_pct,
         }
+    }
 
-        {
-            let cache = self.cache.read().await;
-            if let Some(result) = cache.get(ip) {
-                return result.clone();
-            }
+    /// Snapshot of every cached IP that resolved to a point, without
+    /// triggering new lookups. Order is unspecified.
+    pub(crate) async fn lookup_all_cached(&self) -> Vec<(String, GeoPoint)> {
+        self.cache
+            .read()
+            .iter()
+            .filter_map(|(ip, point)| point.clone().map(|point| (ip.clone(), point)))
+            .collect()
+    }
+
+    /// Writes every resolved cache entry as CSV
+    /// (`ip,latitude,longitude,city,country`), sorted by IP. Returns the
+    /// number of data rows written.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn dump_csv(&self, writer: &mut impl std::io::Write) -> Result<usize> {
+        let mut entries = self.lookup_all_cached().await;
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        writeln!(writer, "ip,latitude,longitude,city,country")?;
+        for (ip, point) in entries.iter() {
+            writeln!(
+                writer,
+                "{},{},{},{},{}",
+                csv_field(ip),
+                point.latitude,
+                point.longitude,
+                csv_field(point.city.as_deref().unwrap_or("")),
+                csv_field(point.country.as_deref().unwrap_or(""))
+            )?;
         }
+        Ok(entries.len())
+    }
 
-        let ip_addr: IpAddr = match ip.parse() {
-            Ok(addr) => addr,
-            Err(_) => {
-                self.cache_write(ip, None).await;
-                return None;
+    /// Imports rows in the [`dump_csv`](Self::dump_csv) format into the cache,
+    /// skipping the header and warning about malformed rows. Returns the
+    /// number of imported entries.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn load_csv(&self, reader: impl std::io::BufRead) -> Result<usize> {
+        let mut entries = Vec::new();
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() || (index == 0 && line.starts_with("ip,")) {
+                continue;
             }
-        };
-
-        let reader = match self.reader.as_ref() {
-            Some(reader) => reader,
-            None => {
-                self.cache_write(ip, None).await;
-                return None;
+            match parse_csv_point(&line) {
+                Some(entry) => entries.push(entry),
+                None => warn!(line = index + 1, "skipping malformed geolocation CSV row"),
             }
-        };
+        }
+        let imported = entries.len();
+        self.with_cache_write(move |cache| {
+            cache.extend(entries.into_iter().map(|(ip, point)| (ip, Some(point))));
+        })
+        .await;
+        Ok(imported)
+    }
 
-        let result = match reader.lookup(ip_addr) {
-            Ok(lookup) => match lookup.decode::<City>() {
-                Ok(Some(city)) => extract_point(&city),
-                Ok(None) => None,
-                Err(err) => {
-                    self.log_lookup_error_once(err);
-                    None
-                }
-            },
-            Err(err) => {
-                self.log_lookup_error_once(err);
-                None
+    /// Looks up `ip` but only keeps country-level detail; see
+    /// [`GeoPoint::anonymized`].
+    pub(crate) async fn lookup_anonymized(&self, ip: &str) -> Option<GeoPoint> {
+        self.lookup(ip).await.map(|point| point.anonymized())
+    }
+
+    /// Tags each network with a cloud provider name (e.g. `"AWS"`) for
+    /// [`cloud_provider`](Self::cloud_provider), so callers can recognise and
+    /// route away from cloud IPs. Tags live apart from the lookup cache, so
+    /// lookups keep returning what the database says. Networks tagged before
+    /// keep their first provider. Returns the number of networks added.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn annotate_cloud_ranges(&self, ranges: &[(ipnet::IpNet, &str)]) -> usize {
+        let mut tagged = self.cloud_ranges.write();
+        let mut added = 0;
+        for (net, provider) in ranges {
+            if !tagged.iter().any(|(existing, _)| existing == net) {
+                tagged.push((*net, provider.to_string()));
+                added += 1;
             }
-        };
-        self.cache_write(ip, result.clone()).await;
-        result
+        }
+        added
+    }
+
+    /// Provider from [`annotate_cloud_ranges`](Self::annotate_cloud_ranges)
+    /// for the most specific tagged network containing `ip`. IPv4-mapped
+    /// IPv6 addresses match IPv4 networks.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn cloud_provider(&self, ip: &str) -> Option<String> {
+        let addr = parse_unmapped_ip(ip)?;
+        self.cloud_ranges
+            .read()
+            .iter()
+            .filter(|(net, _)| net.contains(&addr))
+            .max_by_key(|(net, _)| net.prefix_len())
+            .map(|(_, provider)| provider.clone())
+    }
+
+    /// Takes every cached entry and leaves the cache empty, e.g. to hand the
+    /// warm cache to a replacement process during a rolling restart.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn drain_cache(&self) -> HashMap<String, Option<GeoPoint>> {
+        self.with_cache_write(std::mem::take)
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Drops cached misses so IPs a newer database can resolve are looked up
+    /// again. Returns how many entries were removed.
+    pub(crate) async fn evict_nulls(&self) -> usize {
+        self.with_cache_write(|cache| {
+            let before = cache.len();
+            cache.retain(|_, point| point.is_some());
+            before - cache.len()
+        })
+        .await
+        .unwrap_or(0)
     }
 
     async fn cache_write(&self, ip: &str, value: Option<GeoPoint>) {
-        let mut cache = self.cache.write().await;
-        cache.insert(ip.to_string(), value);
+        let ip = ip.to_string();
+        self.with_cache_write(move |cache| {
+            cache.insert(ip, value);
+        })
+        .await;
+    }
+
+    /// Cache reads take the lock inline since a hit is a single map probe;
+    /// writes run on the blocking pool so a contended write lock never stalls
+    /// an async worker thread.
+    async fn with_cache_write<R, F>(&self, update: F) -> Option<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut HashMap<String, Option<GeoPoint>>) -> R + Send + 'static,
+    {
+        let cache = Arc::clone(&self.cache);
+        match tokio::task::spawn_blocking(move || update(&mut cache.write())).await {
+            Ok(result) => Some(result),
+            Err(err) => {
+                warn!(?err, "failed to update geolocation cache");
+                None
+            }
+        }
     }
 
     fn log_lookup_error_once(&self, err: MaxMindDbError) {
@@ -112,16 +2921,272 @@ impl GeoIpService {
     }
 }
 
+/// `grpc.health.v1.Health` backed by [`GeoIpService::healthy`]; see
+/// [`GeoIpService::into_health_service`].
+#[cfg(feature = "grpc")]
+pub(crate) struct GeoIpHealthService {
+    geoip: GeoIpService,
+}
+
+#[cfg(feature = "grpc")]
+impl GeoIpHealthService {
+    pub(crate) fn new(geoip: GeoIpService) -> Self {
+        Self { geoip }
+    }
+
+    /// Only the overall (`""`) service is known.
+    fn check_service(service: &str) -> Option<tonic::Status> {
+        (!service.is_empty())
+            .then(|| tonic::Status::not_found(format!("unknown service {service:?}")))
+    }
+
+    fn status(&self) -> tonic_health::pb::HealthCheckResponse {
+        use tonic_health::pb::health_check_response::ServingStatus;
+
+        let status = if self.geoip.healthy() {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::NotServing
+        };
+        tonic_health::pb::HealthCheckResponse {
+            status: status as i32,
+        }
+    }
+}
+
+#[cfg(feature = "grpc")]
+#[tonic::async_trait]
+impl tonic_health::pb::health_server::Health for GeoIpHealthService {
+    async fn check(
+        &self,
+        request: tonic::Request<tonic_health::pb::HealthCheckRequest>,
+    ) -> Result<tonic::Response<tonic_health::pb::HealthCheckResponse>, tonic::Status> {
+        if let Some(status) = Self::check_service(&request.get_ref().service) {
+            return Err(status);
+        }
+        Ok(tonic::Response::new(self.status()))
+    }
+
+    type WatchStream = Pin<
+        Box<
+            dyn futures_util::Stream<
+                    Item = Result<tonic_health::pb::HealthCheckResponse, tonic::Status>,
+                > + Send,
+        >,
+    >;
+
+    async fn watch(
+        &self,
+        request: tonic::Request<tonic_health::pb::HealthCheckRequest>,
+    ) -> Result<tonic::Response<Self::WatchStream>, tonic::Status> {
+        if let Some(status) = Self::check_service(&request.get_ref().service) {
+            return Err(status);
+        }
+        let health = GeoIpHealthService::new(self.geoip.clone());
+        let stream = async_stream::stream! {
+            let mut last = health.status();
+            yield Ok(last);
+            let mut interval = tokio::time::interval(HEALTH_WATCH_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let current = health.status();
+                if current != last {
+                    last = current;
+                    yield Ok(current);
+                }
+            }
+        };
+        Ok(tonic::Response::new(Box::pin(stream)))
+    }
+}
+
+/// Tries several editions (for example GeoIP2-City before GeoLite2-City) in
+/// priority order and returns the first hit. Each service keeps its own cache.
+#[derive(Clone)]
+pub(crate) struct MultiEditionGeoIpService(Vec<GeoIpService>);
+
+impl MultiEditionGeoIpService {
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn new(services: Vec<GeoIpService>) -> Self {
+        Self(services)
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn lookup(&self, ip: &str) -> Option<GeoPoint> {
+        for service in self.0.iter() {
+            if let Some(point) = service.lookup(ip).await {
+                return Some(point);
+            }
+        }
+        None
+    }
+}
+
+/// Connection-pool members tagged with the location of their host, so
+/// callers can route to the geographically nearest one.
+pub(crate) struct GeoTaggedPool<T> {
+    members: Vec<(T, Option<GeoPoint>)>,
+}
+
+impl<T> GeoTaggedPool<T> {
+    /// Resolves each member's host, given as an IP or `ip:port`.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) async fn from_pool(hosts: Vec<(String, T)>, service: &GeoIpService) -> Self {
+        let mut members = Vec::with_capacity(hosts.len());
+        for (host, member) in hosts {
+            let ip = host
+                .parse::<SocketAddr>()
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or(host);
+            members.push((member, service.lookup(&ip).await));
+        }
+        Self { members }
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn members(&self) -> &[(T, Option<GeoPoint>)] {
+        &self.members
+    }
+
+    /// The located member closest to `origin`; members without a location
+    /// are never chosen.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn nearest_to(&self, origin: &GeoPoint) -> Option<&T> {
+        self.members
+            .iter()
+            .filter_map(|(member, point)| Some((member, origin.distance_km(point.as_ref()?))))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(member, _)| member)
+    }
+}
+
+/// Deferred [`load_geoip`] for frameworks that construct services
+/// synchronously and initialize them later: `new` only stores the config,
+/// and [`build`](Self::build), or awaiting the builder directly, loads it.
+pub(crate) struct GeoIpServiceBuilder {
+    config: Config,
+}
+
+impl GeoIpServiceBuilder {
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn new(config: Config) -> Self {
+        Self { config }
+    }
+
+    /// Checks the GeoIP settings of the config, then loads the service. A
+    /// misconfiguration is reported before any file or network I/O starts.
+    pub(crate) async fn build(self) -> Result<GeoIpService> {
+        validate_geoip_config(&self.config)?;
+        load_geoip(&self.config).await
+    }
+}
+
+impl IntoFuture for GeoIpServiceBuilder {
+    type Output = Result<GeoIpService>;
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(self.build())
+    }
+}
+
+/// Rejects GeoIP settings that would only fail once loading is under way.
+/// Pure checks only; nothing here touches the filesystem or network.
+fn validate_geoip_config(config: &Config) -> Result<()> {
+    if config.maxmind_db_path.trim().is_empty() {
+        return Err(anyhow!("MAXMIND_DB_PATH must not be empty"));
+    }
+    for (name, url) in [
+        ("MAXMIND_DB_DOWNLOAD_URL", &config.maxmind_db_download_url),
+        ("MAXMIND_FALLBACK_URL", &config.maxmind_fallback_url),
+    ] {
+        if let Some(url) = url {
+            let parsed = url::Url::parse(url).with_context(|| format!("invalid {}", name))?;
+            if !matches!(parsed.scheme(), "http" | "https") {
+                return Err(anyhow!("{} must be an http(s) URL, got {}", name, url));
+            }
+        }
+    }
+    if config.maxmind_license_key.is_some() && config.maxmind_edition_id.trim().is_empty() {
+        return Err(anyhow!(
+            "MAXMIND_EDITION_ID must not be empty when a license key is set"
+        ));
+    }
+    if config.maxmind_download_basic_auth_user.is_some()
+        != config.maxmind_download_basic_auth_password.is_some()
+    {
+        return Err(anyhow!(
+            "MAXMIND_DOWNLOAD_USER and MAXMIND_DOWNLOAD_PASSWORD must be set together"
+        ));
+    }
+    if config.maxmind_max_download_bytes == 0 {
+        return Err(anyhow!(
+            "MAXMIND_MAX_DOWNLOAD_BYTES must be greater than zero"
+        ));
+    }
+    if config.maxmind_download_connect_timeout.is_zero()
+        || config.maxmind_download_read_timeout.is_zero()
+    {
+        return Err(anyhow!(
+            "MaxMind download timeouts must be greater than zero"
+        ));
+    }
+    if config.maxmind_lookup_timeout_ms == Some(0) {
+        return Err(anyhow!(
+            "MAXMIND_LOOKUP_TIMEOUT_MS must be greater than zero"
+        ));
+    }
+    Ok(())
+}
+
 pub(crate) async fn load_geoip(config: &Config) -> Result<GeoIpService> {
+    if config.maxmind_offline {
+        return load_geoip_offline(config).await;
+    }
     let path = resolve_database_path(config)?;
     if !path.exists() {
         info!(
             "MaxMind database not found at {}; downloading",
             path.display()
         );
-        download_database(config, &path).await?;
+        download_database(config, &path, DEFAULT_FALLBACK_DATABASE_URL).await?;
+    }
+    open_geoip_blocking(config, path).await
+}
+
+/// [`load_geoip`] for air-gapped deployments, used by it when
+/// `maxmind_offline` is set: the database must already exist at
+/// `maxmind_db_path`, and a missing file is an error instead of a download.
+pub(crate) async fn load_geoip_offline(config: &Config) -> Result<GeoIpService> {
+    let path = Path::new(&config.maxmind_db_path);
+    if !path.is_file() {
+        return Err(anyhow!(
+            "MaxMind database not found at {}; offline mode (MAXMIND_OFFLINE) never downloads it, so provision the file before starting",
+            path.display()
+        ));
     }
-    match fs::metadata(&path) {
+    open_geoip_blocking(config, path.to_path_buf()).await
+}
+
+/// Runs [`open_geoip`], which reads and parses the database files, on the
+/// blocking pool rather than a runtime worker.
+async fn open_geoip_blocking(config: &Config, path: PathBuf) -> Result<GeoIpService> {
+    let config = config.clone();
+    // Keep the caller's span and subscriber for the load's log events.
+    let span = Span::current();
+    let dispatch = tracing::dispatcher::get_default(Clone::clone);
+    tokio::task::spawn_blocking(move || {
+        tracing::dispatcher::with_default(&dispatch, || {
+            span.in_scope(|| open_geoip(&config, &path))
+        })
+    })
+    .await
+    .context("MaxMind database load task failed")?
+}
+
+fn open_geoip(config: &Config, path: &Path) -> Result<GeoIpService> {
+    match fs::metadata(path) {
         Ok(metadata) => {
             let size = metadata.len();
             info!(
@@ -143,7 +3208,7 @@ pub(crate) async fn load_geoip(config: &Config) -> Result<GeoIpService> {
             );
         }
     };
-    let reader = Reader::open_readfile(&path)
+    let reader = Reader::open_readfile(path)
         .with_context(|| format!("failed to open MaxMind database at {}", path.display()))?;
     info!(
         database_type = %reader.metadata.database_type,
@@ -152,13 +3217,158 @@ pub(crate) async fn load_geoip(config: &Config) -> Result<GeoIpService> {
         node_count = reader.metadata.node_count,
         "MaxMind database metadata loaded"
     );
+    if reader
+        .metadata
+        .database_type
+        .to_lowercase()
+        .contains("anonymous-ip")
+    {
+        info!("MaxMind database is an Anonymous IP database; lookups report anonymity flags only");
+        return Ok(GeoIpService::from_anonymous_ip_reader(reader)
+            .with_overrides(config.geo_overrides.clone()));
+    }
     if !reader.metadata.database_type.to_lowercase().contains("city") {
         warn!(
             database_type = %reader.metadata.database_type,
             "MaxMind database type does not look like a City database; geolocation fields may be empty"
         );
     }
-    Ok(GeoIpService::from_reader(reader))
+    let mut service =
+        GeoIpService::from_reader(reader).with_overrides(config.geo_overrides.clone());
+    if config.trace_not_found {
+        service = service.with_not_found_tracing();
+    }
+    if let Some(timeout_ms) = config.maxmind_lookup_timeout_ms {
+        service = service.with_lookup_timeout(Duration::from_millis(timeout_ms));
+    }
+    if let Some(reader) = open_optional_database(
+        config.maxmind_anon_db_path.as_deref(),
+        "Anonymous IP",
+        "anonymity flags disabled",
+    ) {
+        service = service.with_anonymous_ip_reader(reader);
+    }
+    if let Some(reader) = open_optional_database(
+        config.maxmind_asn_db_path.as_deref(),
+        "ASN",
+        "ASN lookups disabled",
+    ) {
+        service = service.with_asn_reader(reader);
+    }
+    if let Some(reader) = open_optional_database(
+        config.maxmind_domain_db_path.as_deref(),
+        "Domain",
+        "registered domain lookups disabled",
+    ) {
+        service = service.with_domain_reader(reader);
+    }
+    if let Some(path) = config.timezone_fallback_csv.as_deref() {
+        match load_timezone_fallback_csv(path) {
+            Ok(timezones) => {
+                info!(
+                    entries = timezones.len(),
+                    "time zone fallback loaded from {}",
+                    path.display()
+                );
+                service = service.with_timezone_fallback(timezones);
+            }
+            Err(err) => {
+                warn!(
+                    ?err,
+                    "failed to load time zone fallback; points without a database time zone stay empty"
+                );
+            }
+        }
+    }
+    Ok(service)
+}
+
+/// Reads `city,timezone` rows (e.g. exported from GeoNames) into a map from
+/// city name to IANA time zone. The zone is taken after the last comma, so
+/// quote-free city names may themselves contain commas. Blank lines, `#`
+/// comments and a `city,timezone` header row are skipped.
+pub(crate) fn load_timezone_fallback_csv(path: &Path) -> Result<HashMap<String, String>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("failed to read time zone CSV {}", path.display()))?;
+    let mut timezones = HashMap::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (city, timezone) = line.rsplit_once(',').ok_or_else(|| {
+            anyhow!(
+                "time zone CSV {} line {} is not `city,timezone`",
+                path.display(),
+                index + 1
+            )
+        })?;
+        let (city, timezone) = (city.trim(), timezone.trim());
+        if index == 0 && timezone.eq_ignore_ascii_case("timezone") {
+            continue;
+        }
+        if city.is_empty() || timezone.is_empty() {
+            continue;
+        }
+        timezones.insert(city.to_string(), timezone.to_string());
+    }
+    Ok(timezones)
+}
+
+/// Opens one of the optional add-on databases. Returns `None` when no path
+/// is configured, and logs and returns `None` when it cannot be opened so
+/// the service still starts without it.
+fn open_optional_database(
+    path: Option<&str>,
+    name: &str,
+    disabled: &str,
+) -> Option<Reader<Vec<u8>>> {
+    let path = path?;
+    match Reader::open_readfile(path) {
+        Ok(reader) => {
+            info!(
+                database_type = %reader.metadata.database_type,
+                build_epoch = reader.metadata.build_epoch,
+                "MaxMind {} database loaded",
+                name
+            );
+            Some(reader)
+        }
+        Err(err) => {
+            warn!(
+                ?err,
+                "failed to open MaxMind {} database at {}; {}", name, path, disabled
+            );
+            None
+        }
+    }
+}
+
+/// Caches `addr` for `host` in a [`GeoIpService::lookup_domain`] DNS cache.
+/// Entries older than `ttl` are dropped first, then the oldest entry if the
+/// cache is still at [`MAX_DNS_CACHE_ENTRIES`].
+pub(crate) fn remember_resolved_host(
+    cache: &mut HashMap<String, (IpAddr, Instant)>,
+    host: &str,
+    addr: IpAddr,
+    ttl: Duration,
+) {
+    cache.retain(|_, (_, resolved_at)| resolved_at.elapsed() < ttl);
+    if cache.len() >= MAX_DNS_CACHE_ENTRIES && !cache.contains_key(host) {
+        let oldest = cache
+            .iter()
+            .min_by_key(|(_, (_, resolved_at))| *resolved_at)
+            .map(|(host, _)| host.clone());
+        if let Some(oldest) = oldest {
+            cache.remove(&oldest);
+        }
+    }
+    cache.insert(host.to_string(), (addr, Instant::now()));
+}
+
+/// Parses `ip`, unwrapping IPv4-mapped IPv6 addresses to plain IPv4.
+fn parse_unmapped_ip(ip: &str) -> Option<IpAddr> {
+    Some(ip.parse::<IpAddr>().ok()?.to_canonical())
 }
 
 fn resolve_database_path(config: &Config) -> Result<PathBuf> {
@@ -170,15 +3380,33 @@ fn resolve_database_path(config: &Config) -> Result<PathBuf> {
     Ok(path)
 }
 
-async fn download_database(config: &Config, target: &Path) -> Result<()> {
-    let timeout = std::cmp::min(config.request_timeout, Duration::from_secs(5));
+/// Tries `MAXMIND_DB_DOWNLOAD_URL`, then the license-key download, then
+/// `MAXMIND_FALLBACK_URL` or, when that is unset, `default_fallback_url`.
+/// Mirror credentials go only to the two configured mirror URLs, never to
+/// MaxMind or the default fallback.
+pub(crate) async fn download_database(
+    config: &Config,
+    target: &Path,
+    default_fallback_url: &str,
+) -> Result<()> {
     let client = Client::builder()
-        .timeout(timeout)
+        .connect_timeout(config.maxmind_download_connect_timeout)
+        .timeout(config.maxmind_download_read_timeout)
         .build()
         .context("failed to build HTTP client for database download")?;
+    let max_bytes = config.maxmind_max_download_bytes;
+    // Mirror credentials only; the license-key download authenticates via its URL.
+    let mirror_auth = match (
+        config.maxmind_download_basic_auth_user.as_deref(),
+        config.maxmind_download_basic_auth_password.as_deref(),
+    ) {
+        (Some(user), Some(password)) => Some((user, password)),
+        _ => None,
+    };
 
     if let Some(url) = config.maxmind_db_download_url.as_ref() {
-        if let Err(err) = fetch_and_write(&client, url, target, true).await {
+        if let Err(err) = fetch_and_write(&client, url, target, true, max_bytes, mirror_auth).await
+        {
             warn!(
                 ?err,
                 "failed to download MaxMind database from MAXMIND_DB_DOWNLOAD_URL"
@@ -191,7 +3419,7 @@ async fn download_database(config: &Config, target: &Path) -> Result<()> {
 
     if let Some(key) = config.maxmind_license_key.as_ref() {
         let url = format!("https://download.maxmind.com/app/geoip_download?edition_id={}&license_key={}&suffix=tar.gz", config.maxmind_edition_id, key);
-        if let Err(err) = fetch_and_write(&client, &url, target, false).await {
+        if let Err(err) = fetch_and_write(&client, &url, target, false, max_bytes, None).await {
             warn!(?err, "failed to download MaxMind database with license key");
         } else {
             info!("downloaded MaxMind database using license key");
@@ -199,41 +3427,82 @@ async fn download_database(config: &Config, target: &Path) -> Result<()> {
         }
     }
 
-    let url = config
-        .maxmind_fallback_url
-        .as_deref()
-        .unwrap_or("https://raw.githubusercontent.com/maxmind/MaxMind-DB/main/test-data/GeoLite2-City-Test.mmdb");
-    fetch_and_write(&client, url, target, true)
+    let (url, fallback_auth) = match config.maxmind_fallback_url.as_deref() {
+        Some(url) => (url, mirror_auth),
+        None => (default_fallback_url, None),
+    };
+    fetch_and_write(&client, url, target, true, max_bytes, fallback_auth)
         .await
         .context("failed to download fallback MaxMind database")
 }
 
-async fn fetch_and_write(client: &Client, url: &str, target: &Path, raw_mmdb: bool) -> Result<()> {
-    let response = client
-        .get(url)
+fn database_file_name(url: &str) -> Result<(String, bool)> {
+    let parsed = url::Url::parse(url).context("invalid database URL")?;
+    let segment = parsed
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|segment| !segment.is_empty())
+        .ok_or_else(|| anyhow!("cannot derive a database file name from {}", url))?;
+    for suffix in [".tar.gz", ".tgz"] {
+        if let Some(stem) = segment.strip_suffix(suffix) {
+            return Ok((format!("{}.mmdb", stem), false));
+        }
+    }
+    let stem = segment.strip_suffix(".gz").unwrap_or(segment);
+    let stem = stem.strip_suffix(".mmdb").unwrap_or(stem);
+    Ok((format!("{}.mmdb", stem), true))
+}
+
+pub(crate) async fn fetch_and_write(
+    client: &Client,
+    url: &str,
+    target: &Path,
+    raw_mmdb: bool,
+    max_bytes: u64,
+    basic_auth: Option<(&str, &str)>,
+) -> Result<()> {
+    let mut request = client.get(url);
+    if let Some((user, password)) = basic_auth {
+        request = request.basic_auth(user, Some(password));
+    }
+    let mut response = request
         .send()
         .await
         .context("database request failed")?
         .error_for_status()
         .context("database request returned error status")?;
 
-    let bytes = response
-        .bytes()
+    if let Some(length) = response.content_length() {
+        if length > max_bytes {
+            return Err(anyhow!(
+                "database download of {} bytes exceeds the {} byte limit",
+                length,
+                max_bytes
+            ));
+        }
+    }
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
         .await
-        .context("failed to read database body")?;
+        .context("failed to read database body")?
+    {
+        if (bytes.len() + chunk.len()) as u64 > max_bytes {
+            return Err(anyhow!(
+                "database download exceeded the {} byte limit",
+                max_bytes
+            ));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
 
     if raw_mmdb {
         if url.ends_with(".gz") {
-            let mut decoder = GzDecoder::new(Cursor::new(bytes));
-            let mut buf = Vec::new();
-            decoder
-                .read_to_end(&mut buf)
-                .context("failed to decompress database")?;
-            fs::write(target, &buf).context("failed to write database file")?;
-            return Ok(());
+            let decoder = GzDecoder::new(Cursor::new(bytes));
+            let buf = read_limited(decoder, max_bytes).context("failed to decompress database")?;
+            return write_database(target, &buf);
         } else {
-            fs::write(target, &bytes).context("failed to write database file")?;
-            return Ok(());
+            return write_database(target, &bytes);
         }
     }
 
@@ -249,28 +3518,242 @@ async fn fetch_and_write(client: &Client, url: &str, target: &Path, raw_mmdb: bo
             .context("failed to read archive path")?
             .into_owned();
         if path.extension().map(|ext| ext == "mmdb").unwrap_or(false) {
-            let mut buf = Vec::new();
-            entry
-                .read_to_end(&mut buf)
-                .context("failed to read mmdb entry")?;
-            fs::write(target, &buf).context("failed to write database file")?;
-            return Ok(());
+            let buf = read_limited(&mut entry, max_bytes).context("failed to read mmdb entry")?;
+            return write_database(target, &buf);
         }
     }
 
     Err(anyhow!("mmdb file not found in archive"))
 }
 
-fn extract_point(city: &City) -> Option<GeoPoint> {
+/// Reads all of `reader`, failing once the output passes `max_bytes` so a
+/// small compressed download cannot inflate without bound.
+fn read_limited(reader: impl Read, max_bytes: u64) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader
+        .take(max_bytes.saturating_add(1))
+        .read_to_end(&mut buf)?;
+    if buf.len() as u64 > max_bytes {
+        return Err(anyhow!(
+            "decompressed database exceeded the {} byte limit",
+            max_bytes
+        ));
+    }
+    Ok(buf)
+}
+
+/// Fetches `url` as a Nominatim reverse-geocoding response, waiting until
+/// `min_interval` has passed since the previous request; see
+/// [`GeoPoint::fetch_address`].
+#[cfg(feature = "nominatim")]
+pub(crate) async fn fetch_nominatim_address(
+    client: &Client,
+    url: &str,
+    min_interval: Duration,
+) -> Result<Option<String>> {
+    #[derive(Deserialize)]
+    struct ReverseResponse {
+        display_name: Option<String>,
+    }
+
+    // Held for the whole request so concurrent callers queue behind it.
+    let mut last_request = NOMINATIM_LAST_REQUEST.lock().await;
+    if let Some(last) = *last_request {
+        tokio::time::sleep_until((last + min_interval).into()).await;
+    }
+    let result = async {
+        client
+            .get(url)
+            .send()
+            .await
+            .context("Nominatim request failed")?
+            .error_for_status()
+            .context("Nominatim request returned error status")?
+            .json::<ReverseResponse>()
+            .await
+            .context("failed to parse Nominatim response")
+    }
+    .await;
+    *last_request = Some(Instant::now());
+    Ok(result?.display_name)
+}
+
+fn write_database(target: &Path, bytes: &[u8]) -> Result<()> {
+    ensure_mmdb_bytes(bytes)?;
+    fs::write(target, bytes).context("failed to write database file")
+}
+
+/// MMDB files have no leading magic; the format instead ends with a metadata
+/// section introduced by a fixed marker within the last 128 KiB. Checking for
+/// it keeps HTML error pages from mirrors from being written as databases.
+pub(crate) fn ensure_mmdb_bytes(bytes: &[u8]) -> Result<()> {
+    const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+    let tail = &bytes[bytes.len().saturating_sub(128 * 1024)..];
+    if tail
+        .windows(METADATA_MARKER.len())
+        .any(|window| window == METADATA_MARKER)
+    {
+        return Ok(());
+    }
+    let first = bytes.iter().find(|byte| !byte.is_ascii_whitespace());
+    if first == Some(&b'<') {
+        return Err(anyhow!(
+            "response does not look like a MaxMind database (got HTML?)"
+        ));
+    }
+    Err(anyhow!(
+        "response does not look like a MaxMind database (metadata marker missing)"
+    ))
+}
+
+/// Quotes `value` for CSV when it contains a comma, quote, or line break.
+fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// Escapes `value` for use in XML text and attribute values.
+fn xml_escape(value: &str) -> Cow<'_, str> {
+    if !value.contains(['&', '<', '>', '"', '\'']) {
+        return Cow::Borrowed(value);
+    }
+    let mut escaped = String::with_capacity(value.len() + 8);
+    for ch in value.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(ch),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Parses one `ip,latitude,longitude,city,country` row as written by
+/// [`GeoIpService::dump_csv`].
+fn parse_csv_point(line: &str) -> Option<(String, GeoPoint)> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(ch),
+        }
+    }
+    if quoted {
+        return None;
+    }
+    fields.push(field);
+
+    let [ip, latitude, longitude, city, country]: [String; 5] = fields.try_into().ok()?;
+    ip.parse::<IpAddr>().ok()?;
+    let non_empty = |value: String| (!value.is_empty()).then_some(value);
+    let point = GeoPoint::new(
+        latitude.parse().ok()?,
+        longitude.parse().ok()?,
+        non_empty(city),
+        non_empty(country),
+    )
+    .ok()?;
+    Some((ip, point))
+}
+
+#[cfg(feature = "ipc")]
+async fn write_ipc_frame(
+    stream: &mut tokio::net::UnixStream,
+    payload: &[u8],
+) -> std::io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|len| *len <= MAX_IPC_FRAME_BYTES)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "frame too large"))?;
+    stream.write_u32(len).await?;
+    stream.write_all(payload).await?;
+    stream.flush().await
+}
+
+/// `None` when the peer closed the connection between frames.
+#[cfg(feature = "ipc")]
+async fn read_ipc_frame(stream: &mut tokio::net::UnixStream) -> std::io::Result<Option<Vec<u8>>> {
+    use tokio::io::AsyncReadExt;
+
+    let len = match stream.read_u32().await {
+        Ok(len) => len,
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if len > MAX_IPC_FRAME_BYTES {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("cache socket frame of {len} bytes exceeds limit"),
+        ));
+    }
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload).await?;
+    Ok(Some(payload))
+}
+
+/// `country` is where MaxMind geolocates the IP; `registered_country` is
+/// where its ISP registered the block. They usually agree, but anycast and
+/// some cloud ranges only carry the registered country, which is used as a
+/// fallback so those points still report a country.
+///
+/// Builds without `time_zone` fall back to `timezone_fallback`, keyed by the
+/// lowercased English city name.
+fn extract_point(city: &City, timezone_fallback: &HashMap<String, String>) -> Option<GeoPoint> {
     let location = &city.location;
     let latitude = location.latitude?;
     let longitude = location.longitude?;
     let city_name = city.city.names.english.map(|value| value.to_string());
-    let country_name = city.country.names.english.map(|value| value.to_string());
+    let subdivision = city
+        .subdivisions
+        .first()
+        .and_then(|subdivision| subdivision.names.english)
+        .map(|value| value.to_string());
+    let country_name = city
+        .country
+        .names
+        .english
+        .or(city.registered_country.names.english)
+        .map(|value| value.to_string());
     Some(GeoPoint {
         latitude,
         longitude,
-        city: city_name,
+        subdivision,
         country: country_name,
+        country_iso_code: city
+            .country
+            .iso_code
+            .or(city.registered_country.iso_code)
+            .map(|value| value.to_string()),
+        continent: city.continent.names.english.map(|value| value.to_string()),
+        is_anonymous: None,
+        is_vpn: None,
+        is_tor: None,
+        is_anycast: city.traits.is_anycast,
+        accuracy_radius_km: location.accuracy_radius,
+        timezone: location
+            .time_zone
+            .map(|value| value.to_string())
+            .or_else(|| {
+                let city_name = city_name.as_ref()?;
+                timezone_fallback.get(&city_name.to_lowercase()).cloned()
+            }),
+        city: city_name,
     })
 }
@@ -129,6 +129,9 @@ pub(crate) async fn leader_path_handler(
 
     for row in payload.leaders.iter() {
         let coords = match (geoip.as_ref(), row.ip.as_ref()) {
+            (Some(service), Some(ip)) if state.config.enable_anonymization => {
+                service.lookup_anonymized(ip).await
+            }
             (Some(service), Some(ip)) => service.lookup(ip).await,
             _ => None,
         };
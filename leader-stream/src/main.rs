@@ -23,7 +23,7 @@ use tokio::net::TcpListener;
 use tracing::{info, warn};
 
 use crate::background::{run_leader_cache_updater, run_slot_informer, run_subscriber_metrics};
-use crate::config::{read_env_first, Config};
+use crate::config::{read_env_first, read_env_flag, Config};
 use crate::constants::DEFAULT_STATIC_DIR;
 use crate::geo::load_geoip;
 use crate::rpc::RpcClient;
@@ -66,12 +66,7 @@ async fn main() -> Result<()> {
         .unwrap_or_else(|| "/api/leader-stream".to_string());
     let state = AppState::new(config.clone(), rpc, leader_stream_url, geoip);
 
-    let disable_background = env::var("DISABLE_BACKGROUND_TASKS")
-        .map(|value| {
-            let trimmed = value.trim();
-            !trimmed.is_empty() && trimmed != "0"
-        })
-        .unwrap_or(false);
+    let disable_background = read_env_flag("DISABLE_BACKGROUND_TASKS");
 
     if disable_background {
         warn!("background tasks disabled via DISABLE_BACKGROUND_TASKS");
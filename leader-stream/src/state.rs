@@ -83,7 +83,7 @@ impl AppState {
             track_subscribers: AtomicUsize::new(0),
             slot_ms_estimate: AtomicU64::new(API_FALLBACK_SLOT_MS),
             config,
-            geoip: geoip.map(Arc::new),
+            geoip: geoip.map(GeoIpService::into_arc),
         })
     }
 
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -8,11 +9,16 @@ use tower::ServiceExt;
 
 use crate::config::Config;
 use crate::constants::DEFAULT_STATIC_DIR;
-use crate::geo::{GeoIpService, GeoPoint};
+use crate::geo::{
+    download_database, ensure_mmdb_bytes, fetch_and_write, load_geoip, load_geoip_offline,
+    AnonymousIpInfo, AsnInfo, GeoIpService, GeoIpServiceBuilder, GeoPoint, GeoPointError,
+    GeoSource, GeoTaggedPool, GroupBy, LatencyModel, MultiEditionGeoIpService,
+};
 use crate::models::{CachedPayload, CurrentSlotPayload, NodeInfo};
 use crate::rpc::RpcClient;
 use crate::server::build_router;
 use crate::state::AppState;
+use crate::util::format_rfc3339_ms;
 
 fn test_config() -> Config {
     Config {
@@ -32,6 +38,20 @@ fn test_config() -> Config {
         maxmind_edition_id: "GeoLite2-City".to_string(),
         maxmind_db_download_url: None,
         maxmind_fallback_url: None,
+        maxmind_download_connect_timeout: Duration::from_millis(200),
+        maxmind_download_read_timeout: Duration::from_millis(1000),
+        maxmind_max_download_bytes: 1024 * 1024,
+        maxmind_download_basic_auth_user: None,
+        maxmind_download_basic_auth_password: None,
+        maxmind_offline: false,
+        enable_anonymization: false,
+        maxmind_anon_db_path: None,
+        maxmind_asn_db_path: None,
+        maxmind_domain_db_path: None,
+        maxmind_lookup_timeout_ms: None,
+        trace_not_found: false,
+        geo_overrides: HashMap::new(),
+        timezone_fallback_csv: None,
     }
 }
 
@@ -50,6 +70,220 @@ fn test_app(state: Arc<AppState>) -> axum::Router {
     build_router(state, DEFAULT_STATIC_DIR.to_string())
 }
 
+/// Builds a minimal IPv6 MaxMind database (24-bit records) holding `entries`,
+/// so reader lookups can be exercised without downloading a real database.
+/// IPv4 networks are placed in the `::/96` subtree like MaxMind's own builds.
+fn build_test_mmdb(database_type: &str, entries: &[(&str, serde_json::Value)]) -> Vec<u8> {
+    #[derive(Clone, Copy)]
+    enum Record {
+        Empty,
+        Node(usize),
+        Data(usize),
+    }
+
+    let mut nodes = vec![[Record::Empty; 2]];
+    let mut data = Vec::new();
+    let mut offsets = Vec::new();
+    for (index, (network, value)) in entries.iter().enumerate() {
+        let (addr, prefix) = network.split_once('/').expect("network prefix");
+        let prefix: usize = prefix.parse().expect("prefix length");
+        let (bits, prefix) = match addr.parse::<std::net::IpAddr>().expect("network addr") {
+            std::net::IpAddr::V4(v4) => (u32::from(v4) as u128, prefix + 96),
+            std::net::IpAddr::V6(v6) => (u128::from(v6), prefix),
+        };
+        let mut node = 0;
+        for depth in 0..prefix {
+            let bit = ((bits >> (127 - depth)) & 1) as usize;
+            if depth + 1 == prefix {
+                nodes[node][bit] = Record::Data(index);
+                break;
+            }
+            node = match nodes[node][bit] {
+                Record::Node(next) => next,
+                _ => {
+                    nodes.push([Record::Empty; 2]);
+                    let next = nodes.len() - 1;
+                    nodes[node][bit] = Record::Node(next);
+                    next
+                }
+            };
+        }
+        offsets.push(data.len());
+        encode_mmdb_value(&mut data, value);
+    }
+
+    let node_count = nodes.len();
+    let mut out = Vec::new();
+    for node in nodes.iter() {
+        for record in node.iter() {
+            let value = match *record {
+                Record::Empty => node_count,
+                Record::Node(next) => next,
+                Record::Data(index) => node_count + 16 + offsets[index],
+            };
+            out.extend_from_slice(&(value as u32).to_be_bytes()[1..]);
+        }
+    }
+    out.extend_from_slice(&[0u8; 16]);
+    out.extend_from_slice(&data);
+    out.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+    encode_mmdb_value(
+        &mut out,
+        &serde_json::json!({
+            "binary_format_major_version": 2,
+            "binary_format_minor_version": 0,
+            "build_epoch": 1_700_000_000u64,
+            "database_type": database_type,
+            "description": { "en": "leader-stream test database" },
+            "ip_version": 6,
+            "languages": ["en"],
+            "node_count": node_count,
+            "record_size": 24,
+        }),
+    );
+    out
+}
+
+fn encode_mmdb_value(out: &mut Vec<u8>, value: &serde_json::Value) {
+    fn control(out: &mut Vec<u8>, type_num: u8, size: usize) {
+        let (size_bits, extra) = if size < 29 {
+            (size as u8, Vec::new())
+        } else if size < 285 {
+            (29, vec![(size - 29) as u8])
+        } else {
+            (30, ((size - 285) as u16).to_be_bytes().to_vec())
+        };
+        if type_num > 7 {
+            out.push(size_bits);
+            out.push(type_num - 7);
+        } else {
+            out.push((type_num << 5) | size_bits);
+        }
+        out.extend_from_slice(&extra);
+    }
+
+    match value {
+        serde_json::Value::String(text) => {
+            control(out, 2, text.len());
+            out.extend_from_slice(text.as_bytes());
+        }
+        serde_json::Value::Number(number) => {
+            if let Some(int) = number.as_u64() {
+                let bytes = int.to_be_bytes();
+                let skip = bytes.iter().take_while(|byte| **byte == 0).count();
+                control(out, 9, 8 - skip);
+                out.extend_from_slice(&bytes[skip..]);
+            } else {
+                control(out, 3, 8);
+                let float = number.as_f64().expect("float");
+                out.extend_from_slice(&float.to_be_bytes());
+            }
+        }
+        serde_json::Value::Bool(flag) => control(out, 14, *flag as usize),
+        serde_json::Value::Object(map) => {
+            control(out, 7, map.len());
+            for (key, value) in map.iter() {
+                control(out, 2, key.len());
+                out.extend_from_slice(key.as_bytes());
+                encode_mmdb_value(out, value);
+            }
+        }
+        serde_json::Value::Array(items) => {
+            control(out, 11, items.len());
+            for item in items.iter() {
+                encode_mmdb_value(out, item);
+            }
+        }
+        serde_json::Value::Null => panic!("mmdb has no null type"),
+    }
+}
+
+fn test_point(
+    latitude: f64,
+    longitude: f64,
+    city: Option<&str>,
+    country: Option<&str>,
+) -> GeoPoint {
+    GeoPoint {
+        latitude,
+        longitude,
+        city: city.map(str::to_string),
+        subdivision: None,
+        country: country.map(str::to_string),
+        country_iso_code: None,
+        continent: None,
+        is_anonymous: None,
+        is_vpn: None,
+        is_tor: None,
+        is_anycast: None,
+        accuracy_radius_km: None,
+        timezone: None,
+    }
+}
+
+/// Tracing layer that keeps every event as `message key=value ...`, for
+/// asserting on what the service logs.
+#[derive(Clone, Default)]
+struct CapturedEvents(Arc<std::sync::Mutex<Vec<String>>>);
+
+impl CapturedEvents {
+    fn lines(&self) -> Vec<String> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn matching(&self, message: &str) -> Vec<String> {
+        self.lines()
+            .into_iter()
+            .filter(|line| line.starts_with(message))
+            .collect()
+    }
+}
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for CapturedEvents {
+    fn on_event(
+        &self,
+        event: &tracing::Event<'_>,
+        _ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        struct Fields(String, Vec<String>);
+
+        impl tracing::field::Visit for Fields {
+            fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                if field.name() == "message" {
+                    self.0 = format!("{value:?}");
+                } else {
+                    self.1.push(format!("{}={:?}", field.name(), value));
+                }
+            }
+        }
+
+        let mut fields = Fields(String::new(), Vec::new());
+        event.record(&mut fields);
+        let mut line = fields.0;
+        for field in fields.1 {
+            line.push(' ');
+            line.push_str(&field);
+        }
+        self.0.lock().unwrap().push(line);
+    }
+}
+
+fn test_city_reader() -> maxminddb::Reader<Vec<u8>> {
+    let bytes = build_test_mmdb(
+        "GeoIP2-City",
+        &[(
+            "1.2.3.0/24",
+            serde_json::json!({
+                "city": { "names": { "en": "Test City" } },
+                "country": { "iso_code": "TL", "names": { "en": "Testland" } },
+                "location": { "latitude": 10.5, "longitude": -20.25 },
+                "subdivisions": [{ "iso_code": "TP", "names": { "en": "Test Province" } }],
+            }),
+        )],
+    );
+    maxminddb::Reader::from_source(bytes).expect("test mmdb")
+}
+
 #[tokio::test]
 async fn health_endpoint_returns_ok() {
     let app = test_app(test_state());
@@ -121,7 +355,7 @@ async fn current_slot_returns_cached_payload() {
 #[tokio::test]
 async fn next_leaders_returns_leaders_payload() {
     let state = test_state();
-    let leaders = vec!["leader-1", "leader-2", "leader-3"];
+    let leaders = ["leader-1", "leader-2", "leader-3"];
     {
         let mut cache = state.leader_cache.write().await;
         cache.start_slot = Some(100);
@@ -263,12 +497,12 @@ async fn leader_path_returns_geolocated_entries() {
     let mut cache_map = std::collections::HashMap::new();
     cache_map.insert(
         "1.2.3.4".to_string(),
-        Some(GeoPoint {
-            latitude: 10.5,
-            longitude: -20.25,
-            city: Some("Test City".to_string()),
-            country: Some("Testland".to_string()),
-        }),
+        Some(test_point(
+            10.5,
+            -20.25,
+            Some("Test City"),
+            Some("Testland"),
+        )),
     );
     let geoip = GeoIpService::from_static(cache_map);
     let state = AppState::new(config, rpc, "/api/leader-stream".to_string(), Some(geoip));
@@ -312,3 +546,2949 @@ async fn leader_path_returns_geolocated_entries() {
     assert_eq!(value["path"][0]["longitude"], -20.25);
     assert_eq!(value["path"][0]["city"], "Test City");
 }
+
+#[tokio::test]
+async fn lookup_anonymized_drops_city_and_rounds_coordinates() {
+    let mut cache_map = std::collections::HashMap::new();
+    cache_map.insert(
+        "1.2.3.4".to_string(),
+        Some(test_point(
+            51.5074,
+            -0.1278,
+            Some("London"),
+            Some("United Kingdom"),
+        )),
+    );
+    let geoip = GeoIpService::from_static(cache_map);
+
+    let point = geoip.lookup_anonymized("1.2.3.4").await.expect("point");
+    assert_eq!(point.latitude, 51.5);
+    assert_eq!(point.longitude, -0.1);
+    assert!(point.city.is_none());
+    assert_eq!(point.country.as_deref(), Some("United Kingdom"));
+    assert!(geoip.lookup_anonymized("5.6.7.8").await.is_none());
+}
+
+#[tokio::test]
+async fn lookup_resolves_direct_and_ipv4_mapped_addresses() {
+    let geoip = GeoIpService::from_reader(test_city_reader());
+
+    let direct = geoip.lookup("1.2.3.4").await.expect("direct lookup");
+    assert_eq!(direct.city.as_deref(), Some("Test City"));
+    assert_eq!(direct.subdivision.as_deref(), Some("Test Province"));
+    assert_eq!(direct.latitude, 10.5);
+
+    let mapped = geoip.lookup("::ffff:1.2.3.4").await.expect("mapped lookup");
+    assert_eq!(mapped.country.as_deref(), Some("Testland"));
+    assert_eq!(mapped.longitude, -20.25);
+
+    assert!(geoip.lookup("::ffff:5.6.7.8").await.is_none());
+}
+
+#[test]
+fn geo_overrides_skip_entries_with_invalid_coordinates() {
+    let overrides = crate::config::parse_geo_overrides(
+        r#"{
+            "10.0.0.7": { "latitude": 52.5, "longitude": 13.4, "city": "Berlin", "country": "Germany" },
+            "10.0.0.8": { "latitude": 91.0, "longitude": 13.4, "city": null, "country": null },
+            "10.0.0.9": { "latitude": 52.5, "longitude": -180.5, "city": null, "country": null }
+        }"#,
+    );
+    assert_eq!(overrides.len(), 1);
+    assert_eq!(overrides["10.0.0.7"].city.as_deref(), Some("Berlin"));
+    assert!(crate::config::parse_geo_overrides("[1, 2]").is_empty());
+}
+
+#[tokio::test]
+async fn geo_overrides_take_precedence_over_database() {
+    let overrides = HashMap::from([
+        (
+            "10.0.0.7".to_string(),
+            test_point(52.5, 13.4, Some("Berlin"), Some("Germany")),
+        ),
+        (
+            "1.2.3.4".to_string(),
+            test_point(1.0, 2.0, Some("Override"), None),
+        ),
+    ]);
+    let geoip = GeoIpService::from_reader(test_city_reader()).with_overrides(overrides);
+
+    let staging = geoip.lookup("10.0.0.7").await.expect("override");
+    assert_eq!(staging.city.as_deref(), Some("Berlin"));
+    let overridden = geoip.lookup("1.2.3.4").await.expect("override");
+    assert_eq!(overridden.city.as_deref(), Some("Override"));
+    let live = geoip.lookup("1.2.3.5").await.expect("database hit");
+    assert_eq!(live.city.as_deref(), Some("Test City"));
+}
+
+#[test]
+fn geo_point_new_rejects_impossible_coordinates() {
+    let point = GeoPoint::new(51.5, -0.1, Some("London".to_string()), None).expect("valid");
+    assert_eq!(point.latitude(), 51.5);
+    assert_eq!(point.longitude(), -0.1);
+    assert_eq!(point.city(), Some("London"));
+    assert_eq!(point.country(), None);
+
+    assert_eq!(
+        GeoPoint::new(200.0, 0.0, None, None).unwrap_err(),
+        GeoPointError::InvalidLatitude(200.0)
+    );
+    assert_eq!(
+        GeoPoint::new(0.0, -400.0, None, None).unwrap_err(),
+        GeoPointError::InvalidLongitude(-400.0)
+    );
+    assert!(GeoPoint::new(f64::NAN, 0.0, None, None).is_err());
+}
+
+#[test]
+fn fuzzy_match_city_picks_closest_name() {
+    let candidates = vec![
+        test_point(48.85, 2.35, Some("Paris"), Some("France")),
+        test_point(52.52, 13.4, Some("Berlin"), Some("Germany")),
+        test_point(0.0, 0.0, None, None),
+    ];
+
+    let best = GeoPoint::fuzzy_match_city(&candidates, "berln").expect("close match");
+    assert_eq!(best.city.as_deref(), Some("Berlin"));
+    assert!(GeoPoint::fuzzy_match_city(&candidates, "Tokyo").is_none());
+    assert!(GeoPoint::fuzzy_match_city(&[], "Paris").is_none());
+}
+
+#[test]
+fn weighted_centroid_leans_toward_heavier_points() {
+    let west = test_point(0.0, -10.0, None, None);
+    let east = test_point(0.0, 10.0, None, None);
+
+    let even =
+        GeoPoint::weighted_centroid(&[(west.clone(), 1.0), (east.clone(), 1.0)]).expect("centroid");
+    assert!(even.latitude.abs() < 1e-9 && even.longitude.abs() < 1e-9);
+    let skewed =
+        GeoPoint::weighted_centroid(&[(west.clone(), 1.0), (east.clone(), 3.0)]).expect("centroid");
+    assert!(skewed.longitude > 4.0 && skewed.longitude < 6.0);
+
+    let across_antimeridian = GeoPoint::weighted_centroid(&[
+        (test_point(0.0, 179.0, None, None), 1.0),
+        (test_point(0.0, -179.0, None, None), 1.0),
+    ])
+    .expect("centroid");
+    assert!((across_antimeridian.longitude.abs() - 180.0).abs() < 1e-6);
+
+    assert!(GeoPoint::weighted_centroid(&[]).is_none());
+    assert!(GeoPoint::weighted_centroid(&[(west, 0.0), (east, 0.0)]).is_none());
+}
+
+#[test]
+fn format_rfc3339_ms_renders_utc() {
+    assert_eq!(format_rfc3339_ms(0), "1970-01-01T00:00:00.000Z");
+    assert_eq!(
+        format_rfc3339_ms(1_709_210_096_789),
+        "2024-02-29T12:34:56.789Z"
+    );
+}
+
+#[test]
+fn administrative_area_joins_present_components() {
+    let area = |city: Option<&str>, subdivision: Option<&str>, country: Option<&str>| {
+        GeoPoint {
+            subdivision: subdivision.map(str::to_string),
+            ..test_point(0.0, 0.0, city, country)
+        }
+        .administrative_area()
+    };
+
+    assert_eq!(
+        area(Some("Austin"), Some("Texas"), Some("United States")),
+        "Austin, Texas, United States"
+    );
+    assert_eq!(area(Some("Austin"), Some("Texas"), None), "Austin, Texas");
+    assert_eq!(
+        area(Some("Austin"), None, Some("United States")),
+        "Austin, United States"
+    );
+    assert_eq!(
+        area(None, Some("Texas"), Some("United States")),
+        "Texas, United States"
+    );
+    assert_eq!(area(Some("Austin"), None, None), "Austin");
+    assert_eq!(area(None, Some("Texas"), None), "Texas");
+    assert_eq!(area(None, None, Some("United States")), "United States");
+    assert_eq!(area(None, None, None), "");
+
+    assert_eq!(
+        area(Some("New York"), Some("New York"), Some("United States")),
+        "New York, United States"
+    );
+    assert_eq!(
+        area(Some("Singapore"), None, Some("Singapore")),
+        "Singapore"
+    );
+    assert_eq!(area(Some(" "), Some("Texas"), None), "Texas");
+}
+
+#[test]
+fn compact_array_round_trips_coordinates_and_shrinks_json() {
+    let points: Vec<GeoPoint> = (0..1000)
+        .map(|i| {
+            test_point(
+                f64::from(i) / 20.0,
+                -f64::from(i) / 10.0,
+                Some("Frankfurt am Main"),
+                Some("Germany"),
+            )
+        })
+        .collect();
+
+    for point in points.iter() {
+        let restored = GeoPoint::from_compact_array(point.to_compact_array());
+        assert_eq!(restored.latitude, point.latitude);
+        assert_eq!(restored.longitude, point.longitude);
+        assert!(restored.city.is_none());
+    }
+
+    let full = serde_json::to_vec(&points).expect("full json").len();
+    let compact: Vec<[f64; 2]> = points.iter().map(GeoPoint::to_compact_array).collect();
+    let compact = serde_json::to_vec(&compact).expect("compact json").len();
+    assert!(
+        compact * 4 < full,
+        "compact {compact} bytes vs full {full} bytes"
+    );
+}
+
+#[test]
+fn to_nominatim_url_embeds_coordinates() {
+    let rio = test_point(-22.9068, -43.1729, Some("Rio de Janeiro"), Some("Brazil"));
+    let url = rio.to_nominatim_url();
+    assert!(url.starts_with("https://nominatim.openstreetmap.org/reverse?"));
+    assert!(url.contains("lat=-22.9068"));
+    assert!(url.contains("lon=-43.1729"));
+    assert!(url.ends_with("&format=json"));
+}
+
+#[test]
+fn to_uri_encoded_coords_round_trips_through_url_parsing() {
+    let london = test_point(51.507, -0.128, Some("London"), Some("United Kingdom"));
+    assert_eq!(london.to_uri_encoded_coords(), "lat=51.507&lon=-0.128");
+
+    let precise = test_point(-33.868820123456, 151.209295987654, None, None);
+    let query = precise.to_uri_encoded_coords();
+    assert_eq!(query, "lat=-33.868820123456&lon=151.209295987654");
+    let url = url::Url::parse(&format!("https://example.com/map?{query}")).expect("valid url");
+    let pairs: HashMap<_, _> = url.query_pairs().into_owned().collect();
+    assert_eq!(pairs.len(), 2);
+    assert_eq!(pairs["lat"].parse::<f64>().ok(), Some(-33.868820123456));
+    assert_eq!(pairs["lon"].parse::<f64>().ok(), Some(151.209295987654));
+}
+
+#[test]
+fn snap_to_grid_rounds_to_cell_and_merges_cluster() {
+    let london = test_point(51.5074, -0.1278, Some("London"), Some("United Kingdom"));
+    let snapped = london.snap_to_grid(1.0);
+    assert_eq!(snapped.to_compact_array(), [52.0, 0.0]);
+    assert_eq!(snapped.city(), Some("London"));
+
+    let cluster = [(51.6, 0.4), (52.4, -0.4), (51.9, 0.1)];
+    for (lat, lon) in cluster {
+        let point = test_point(lat, lon, None, None).snap_to_grid(1.0);
+        assert_eq!(point.to_compact_array(), [52.0, 0.0]);
+    }
+    assert_eq!(
+        test_point(88.0, 179.0, None, None)
+            .snap_to_grid(5.0)
+            .to_compact_array(),
+        [90.0, 180.0]
+    );
+}
+
+#[test]
+fn to_svg_pin_projects_and_parses_as_xml() {
+    let tokyo = test_point(35.6762, 139.6503, Some("Tokyo"), Some("Japan"));
+    let pin = tokyo.to_svg_pin(Some("Tokyo <R&D>"));
+    let doc = format!(r#"<svg xmlns="http://www.w3.org/2000/svg">{pin}</svg>"#);
+    let parsed = roxmltree::Document::parse(&doc).expect("valid xml");
+
+    let circle = parsed
+        .descendants()
+        .find(|node| node.has_tag_name("circle"))
+        .expect("circle");
+    assert_eq!(circle.attribute("cx"), Some("887.92"));
+    assert_eq!(circle.attribute("cy"), Some("150.90"));
+    let text = parsed
+        .descendants()
+        .find(|node| node.has_tag_name("text"))
+        .expect("text");
+    assert_eq!(text.text(), Some("Tokyo <R&D>"));
+
+    let bare = test_point(-90.0, -180.0, None, None).to_svg_pin(None);
+    assert_eq!(bare, r#"<circle cx="0.00" cy="500.00" r="4" class="pin"/>"#);
+}
+
+#[test]
+fn estimated_latency_grows_linearly_with_distance() {
+    let london = test_point(51.5074, -0.1278, Some("London"), Some("United Kingdom"));
+    let new_york = test_point(40.7128, -74.006, Some("New York"), Some("United States"));
+
+    let model = LatencyModel::default();
+    assert_eq!(london.estimated_latency_ms(&london, model), 1.0);
+    let transatlantic = london.estimated_latency_ms(&new_york, model);
+    assert!(
+        (transatlantic - 56.7).abs() < 0.5,
+        "latency {transatlantic}"
+    );
+    let slow = LatencyModel {
+        base_ms: 5.0,
+        ms_per_km: 0.02,
+    };
+    let doubled = london.estimated_latency_ms(&new_york, slow);
+    assert!((doubled - (5.0 + 2.0 * (transatlantic - 1.0))).abs() < 1e-9);
+}
+
+#[test]
+fn to_dms_formats_berlin() {
+    let berlin = test_point(52.52, 13.405, Some("Berlin"), Some("Germany"));
+    assert_eq!(berlin.to_dms(), "52°31′12″N 13°24′18″E");
+}
+
+#[test]
+fn to_dms_handles_southern_and_western_hemispheres() {
+    let rio = test_point(-22.9068, -43.1729, Some("Rio de Janeiro"), Some("Brazil"));
+    assert_eq!(rio.to_dms(), "22°54′24″S 43°10′22″W");
+    let almost_zero = test_point(-0.00001, 0.0, None, None);
+    assert_eq!(almost_zero.to_dms(), "0°0′0″N 0°0′0″E");
+}
+
+#[test]
+fn bearing_between_london_and_new_york() {
+    let london = test_point(51.5074, -0.1278, Some("London"), None);
+    let new_york = test_point(40.7128, -74.0060, Some("New York"), None);
+
+    assert!((london.bearing_to(&new_york) - 288.0).abs() < 1.0);
+    assert!((new_york.bearing_to(&london) - 51.0).abs() < 1.0);
+}
+
+#[test]
+fn database_info_reports_age() {
+    let geoip = GeoIpService::from_reader(test_city_reader());
+    let info = geoip.database_info().expect("database info");
+    assert_eq!(info.database_type, "GeoIP2-City");
+    assert_eq!(info.build_epoch, 1_700_000_000);
+    assert_eq!(info.ip_version, 6);
+
+    assert!(info.age() > Duration::from_secs(365 * 24 * 3600));
+    assert!(info.is_stale(Duration::from_secs(30 * 24 * 3600)));
+    assert!(!info.is_stale(Duration::from_secs(100 * 365 * 24 * 3600)));
+    assert!(GeoIpService::new_noop().database_info().is_none());
+}
+
+#[tokio::test]
+async fn noop_service_never_resolves() {
+    let geoip = GeoIpService::new_noop();
+    assert!(geoip.lookup("1.2.3.4").await.is_none());
+    assert!(geoip.lookup_anonymous_ip("1.2.3.4").is_none());
+}
+
+#[tokio::test]
+async fn lookup_with_metadata_reports_cache_provenance() {
+    let geoip = GeoIpService::from_reader(test_city_reader());
+
+    let (point, source) = geoip.lookup_with_metadata("1.2.3.4").await;
+    assert!(point.is_some());
+    assert_eq!(source, GeoSource::Live);
+    let (point, source) = geoip.lookup_with_metadata("1.2.3.4").await;
+    assert!(point.is_some());
+    assert_eq!(source, GeoSource::Cache);
+
+    let (point, source) = geoip.lookup_with_metadata("9.9.9.9").await;
+    assert!(point.is_none());
+    assert_eq!(source, GeoSource::NotFound);
+
+    assert_eq!(geoip.total_lookup_count(), 3);
+    assert_eq!(geoip.total_cache_hit_count(), 1);
+}
+
+#[tokio::test]
+async fn lookup_falls_back_to_registered_country() {
+    let bytes = build_test_mmdb(
+        "GeoIP2-City",
+        &[(
+            "8.8.8.0/24",
+            serde_json::json!({
+                "registered_country": { "iso_code": "US", "names": { "en": "United States" } },
+                "location": { "latitude": 37.75, "longitude": -97.82 },
+            }),
+        )],
+    );
+    let geoip = GeoIpService::from_reader(maxminddb::Reader::from_source(bytes).expect("mmdb"));
+
+    let point = geoip.lookup("8.8.8.8").await.expect("anycast lookup");
+    assert_eq!(point.country.as_deref(), Some("United States"));
+    assert!(point.city.is_none());
+}
+
+#[tokio::test]
+async fn lookup_domain_resolves_hostnames_and_literals() {
+    let mut cache_map = HashMap::new();
+    for ip in ["127.0.0.1", "::1"] {
+        cache_map.insert(
+            ip.to_string(),
+            Some(test_point(1.0, 2.0, Some("Loopback"), None)),
+        );
+    }
+    let geoip = GeoIpService::from_static(cache_map).with_dns_ttl(Duration::from_secs(600));
+
+    assert!(geoip.lookup_domain("127.0.0.1").await.is_some());
+    let point = geoip.lookup_domain("localhost").await.expect("localhost");
+    assert_eq!(point.city.as_deref(), Some("Loopback"));
+    assert!(geoip.lookup_domain("localhost").await.is_some());
+    assert!(geoip
+        .lookup_domain("does-not-exist.invalid")
+        .await
+        .is_none());
+}
+
+#[test]
+fn dns_cache_drops_expired_and_oldest_hosts() {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Instant;
+
+    let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let ttl = Duration::from_secs(60);
+    let now = Instant::now();
+    let mut cache = HashMap::new();
+    cache.insert(
+        "expired.example".to_string(),
+        (addr, now - Duration::from_secs(120)),
+    );
+    crate::geo::remember_resolved_host(&mut cache, "fresh.example", addr, ttl);
+    assert!(!cache.contains_key("expired.example"));
+    assert!(cache.contains_key("fresh.example"));
+
+    cache.clear();
+    for i in 0..crate::geo::MAX_DNS_CACHE_ENTRIES {
+        let age = Duration::from_secs(1) + Duration::from_millis(i as u64);
+        cache.insert(format!("host-{i}.example"), (addr, now - age));
+    }
+    let oldest = format!("host-{}.example", crate::geo::MAX_DNS_CACHE_ENTRIES - 1);
+    crate::geo::remember_resolved_host(&mut cache, "new.example", addr, ttl);
+    assert_eq!(cache.len(), crate::geo::MAX_DNS_CACHE_ENTRIES);
+    assert!(cache.contains_key("new.example"));
+    assert!(!cache.contains_key(&oldest));
+    assert!(cache.contains_key("host-0.example"));
+}
+
+#[tokio::test]
+async fn lookup_scored_inverts_accuracy_radius() {
+    let bytes = build_test_mmdb(
+        "GeoIP2-City",
+        &[(
+            "5.6.7.0/24",
+            serde_json::json!({
+                "location": { "latitude": 3.5, "longitude": 4.5, "accuracy_radius": 20 },
+            }),
+        )],
+    );
+    let geoip = GeoIpService::from_reader(maxminddb::Reader::from_source(bytes).expect("mmdb"));
+
+    let (point, score) = geoip.lookup_scored("5.6.7.8").await.expect("scored lookup");
+    assert_eq!(point.accuracy_radius_km, Some(20));
+    assert_eq!(score, 0.05);
+    let unscored = GeoIpService::from_reader(test_city_reader());
+    assert_eq!(
+        unscored.lookup_scored("1.2.3.4").await.map(|(_, s)| s),
+        Some(1.0)
+    );
+    assert!(unscored.lookup_scored("9.9.9.9").await.is_none());
+}
+
+#[tokio::test]
+async fn lookup_reports_anycast_trait() {
+    let bytes = build_test_mmdb(
+        "GeoIP2-City",
+        &[(
+            "1.1.1.0/24",
+            serde_json::json!({
+                "location": { "latitude": -33.49, "longitude": 143.21 },
+                "traits": { "is_anycast": true },
+            }),
+        )],
+    );
+    let geoip = GeoIpService::from_reader(maxminddb::Reader::from_source(bytes).expect("mmdb"));
+
+    let point = geoip.lookup("1.1.1.1").await.expect("anycast lookup");
+    assert_eq!(point.is_anycast, Some(true));
+    let unicast = GeoIpService::from_reader(test_city_reader());
+    assert_eq!(
+        unicast.lookup("1.2.3.4").await.expect("point").is_anycast,
+        None
+    );
+}
+
+#[tokio::test]
+async fn lookup_from_socket_addr_unwraps_mapped_ipv4() {
+    let geoip = GeoIpService::from_reader(test_city_reader());
+    let v4: std::net::SocketAddr = "1.2.3.4:443".parse().expect("addr");
+    let mapped: std::net::SocketAddr = "[::ffff:1.2.3.4]:443".parse().expect("addr");
+
+    assert!(geoip.lookup_from_socket_addr(v4).await.is_some());
+    let (_, source) = geoip.lookup_with_metadata("1.2.3.4").await;
+    assert_eq!(source, GeoSource::Cache);
+    assert!(geoip.lookup_from_socket_addr(mapped).await.is_some());
+    assert_eq!(geoip.total_cache_hit_count(), 2);
+}
+
+#[tokio::test]
+async fn lookup_with_extractor_resolves_embedded_ip() {
+    struct Handshake {
+        client: String,
+    }
+    let geoip = GeoIpService::from_reader(test_city_reader());
+    let handshake = Handshake {
+        client: "1.2.3.4".to_string(),
+    };
+
+    let point = geoip
+        .lookup_with_extractor(&handshake, |h| h.client.clone())
+        .await;
+    assert_eq!(point.map(|p| p.latitude), Some(10.5));
+    let addr: std::net::SocketAddr = "9.9.9.9:80".parse().expect("addr");
+    assert!(geoip
+        .lookup_with_extractor(&addr, |a| a.ip().to_string())
+        .await
+        .is_none());
+}
+
+#[tokio::test]
+async fn lookup_compare_reports_shared_region_and_distance() {
+    let geoip = GeoIpService::new_noop().with_overrides(HashMap::from([
+        (
+            "10.0.0.1".to_string(),
+            test_point(52.52, 13.405, Some("Berlin"), Some("Germany")),
+        ),
+        (
+            "10.0.0.2".to_string(),
+            test_point(48.1351, 11.582, Some("Munich"), Some("Germany")),
+        ),
+        (
+            "10.0.0.3".to_string(),
+            test_point(52.52, 13.405, Some("Berlin"), Some("Germany")),
+        ),
+    ]));
+
+    let cross_city = geoip.lookup_compare("10.0.0.1", "10.0.0.2").await;
+    assert!(cross_city.same_country);
+    assert!(!cross_city.same_city);
+    let distance = cross_city.distance_km.expect("distance");
+    assert!((distance - 504.0).abs() < 5.0, "distance {distance}");
+
+    let same = geoip.lookup_compare("10.0.0.1", "10.0.0.3").await;
+    assert!(same.same_city);
+    assert_eq!(same.distance_km, Some(0.0));
+
+    let unknown = geoip.lookup_compare("10.0.0.1", "9.9.9.9").await;
+    assert!(!unknown.same_country && unknown.distance_km.is_none());
+    assert!(unknown.a.is_some() && unknown.b.is_none());
+}
+
+#[tokio::test]
+async fn build_routing_table_groups_ips_by_region() {
+    let bytes = build_test_mmdb(
+        "GeoIP2-City",
+        &[
+            (
+                "1.0.0.0/8",
+                serde_json::json!({
+                    "city": { "names": { "en": "Paris" } },
+                    "continent": { "code": "EU", "names": { "en": "Europe" } },
+                    "country": { "names": { "en": "France" } },
+                    "location": { "latitude": 48.85, "longitude": 2.35 },
+                }),
+            ),
+            (
+                "2.0.0.0/8",
+                serde_json::json!({
+                    "city": { "names": { "en": "Paris" } },
+                    "continent": { "code": "NA", "names": { "en": "North America" } },
+                    "country": { "names": { "en": "United States" } },
+                    "location": { "latitude": 33.66, "longitude": -95.55 },
+                }),
+            ),
+        ],
+    );
+    let geoip = GeoIpService::from_reader(maxminddb::Reader::from_source(bytes).expect("mmdb"));
+    let ips = ["1.0.0.1", "2.0.0.1", "9.9.9.9", "1.0.0.2"];
+
+    let by_city = geoip.build_routing_table(&ips, GroupBy::City).await;
+    assert_eq!(by_city["Paris, France"], ["1.0.0.1", "1.0.0.2"]);
+    assert_eq!(by_city["Paris, United States"], ["2.0.0.1"]);
+    assert_eq!(by_city["Unknown"], ["9.9.9.9"]);
+    let by_continent = geoip.build_routing_table(&ips, GroupBy::Continent).await;
+    assert_eq!(by_continent["Europe"].len(), 2);
+    assert_eq!(by_continent["North America"], ["2.0.0.1"]);
+    let by_country = geoip.build_routing_table(&ips, GroupBy::Country).await;
+    assert_eq!(by_country.len(), 3);
+}
+
+#[tokio::test]
+async fn lookup_diff_reports_changes_between_lookups() {
+    let geoip = GeoIpService::new_noop().with_overrides(HashMap::from([(
+        "10.0.0.1".to_string(),
+        test_point(52.52, 13.405, Some("Berlin"), Some("Germany")),
+    )]));
+
+    let first = geoip.lookup_diff("10.0.0.1").await;
+    assert!(!first.changed && first.previous.is_none());
+    let again = geoip.lookup_diff("10.0.0.1").await;
+    assert!(!again.changed);
+    assert_eq!(again.previous, again.current);
+
+    geoip.drain_cache().await;
+    let moved = geoip.with_overrides(HashMap::from([(
+        "10.0.0.1".to_string(),
+        test_point(48.8566, 2.3522, Some("Paris"), Some("France")),
+    )]));
+    let diff = moved.lookup_diff("10.0.0.1").await;
+    assert!(diff.changed);
+    assert_eq!(
+        diff.previous.and_then(|p| p.city),
+        Some("Berlin".to_string())
+    );
+    assert_eq!(diff.current.and_then(|p| p.city), Some("Paris".to_string()));
+    assert!(!moved.lookup_diff("10.0.0.1").await.changed);
+}
+
+#[tokio::test]
+async fn count_by_region_counts_every_cached_ip() {
+    let mut entries = HashMap::new();
+    for (ip, city, country) in [
+        ("10.0.0.1", "Berlin", "Germany"),
+        ("10.0.0.2", "Munich", "Germany"),
+        ("10.0.0.3", "Paris", "France"),
+    ] {
+        entries.insert(
+            ip.to_string(),
+            Some(test_point(0.0, 0.0, Some(city), Some(country))),
+        );
+    }
+    entries.insert("10.0.0.4".to_string(), None);
+    entries.insert(
+        "10.0.0.5".to_string(),
+        Some(test_point(0.0, 0.0, None, None)),
+    );
+    let geoip = GeoIpService::from_static(entries);
+
+    let counts = geoip.count_by_region(GroupBy::Country).await;
+    assert_eq!(counts.len(), 3);
+    assert_eq!(counts["Germany"], 2);
+    assert_eq!(counts["Unknown"], 2);
+    assert_eq!(
+        geoip.count_by_region_sorted(GroupBy::Country).await,
+        [
+            ("Germany".to_string(), 2),
+            ("Unknown".to_string(), 2),
+            ("France".to_string(), 1),
+        ]
+    );
+    assert_eq!(geoip.count_by_region(GroupBy::City).await.len(), 4);
+}
+
+#[tokio::test]
+async fn lookup_with_fallback_caches_fallback_hits() {
+    let primary = GeoIpService::new_noop();
+    let fallback = GeoIpService::from_reader(test_city_reader());
+
+    let point = primary.lookup_with_fallback("1.2.3.4", &fallback).await;
+    assert_eq!(point.map(|p| p.latitude), Some(10.5));
+    assert_eq!(
+        primary.lookup("1.2.3.4").await.map(|p| p.latitude),
+        Some(10.5)
+    );
+    assert!(primary
+        .lookup_with_fallback("1.2.3.4", &fallback)
+        .await
+        .is_some());
+    assert_eq!(fallback.total_lookup_count(), 1);
+    assert!(primary
+        .lookup_with_fallback("9.9.9.9", &fallback)
+        .await
+        .is_none());
+}
+
+#[tokio::test]
+async fn filter_stream_drops_blocked_countries() {
+    use futures_util::StreamExt;
+
+    let geoip = GeoIpService::from_reader(test_city_reader())
+        .with_overrides(HashMap::from([(
+            "10.0.0.1".to_string(),
+            test_point(48.8566, 2.3522, Some("Paris"), Some("France")),
+        )]))
+        .with_blocked_countries(["TESTLAND".to_string()].into());
+    let connections = futures_util::stream::iter([
+        ("1.2.3.4", 1),
+        ("10.0.0.1", 2),
+        ("9.9.9.9", 3),
+        ("1.2.3.5", 4),
+    ]);
+
+    let admitted: Vec<_> = geoip
+        .filter_stream(connections, |(ip, _)| ip)
+        .map(|(_, id)| id)
+        .collect()
+        .await;
+    assert_eq!(admitted, [2, 3]);
+}
+
+#[tokio::test]
+async fn score_candidates_prefers_nearby_leaders() {
+    let geoip = GeoIpService::new_noop().with_overrides(HashMap::from([
+        (
+            "10.0.0.1".to_string(),
+            test_point(52.52, 13.405, Some("Berlin"), Some("Germany")),
+        ),
+        (
+            "10.0.0.2".to_string(),
+            test_point(48.1351, 11.582, Some("Munich"), Some("Germany")),
+        ),
+        (
+            "10.0.0.3".to_string(),
+            test_point(35.6762, 139.6503, Some("Tokyo"), Some("Japan")),
+        ),
+    ]));
+    let candidates = [("10.0.0.3", 100.0), ("9.9.9.9", 100.0), ("10.0.0.2", 100.0)];
+
+    let ranked = geoip.score_candidates("10.0.0.1", &candidates).await;
+    let order: Vec<_> = ranked.iter().map(|(ip, _)| *ip).collect();
+    assert_eq!(order, ["10.0.0.2", "10.0.0.3", "9.9.9.9"]);
+    assert!((ranked[0].1 - 93.96).abs() < 0.1, "score {}", ranked[0].1);
+
+    let unlocated = geoip.score_candidates("8.8.8.8", &candidates).await;
+    assert!(unlocated.iter().all(|(_, score)| *score == 100.0));
+}
+
+#[tokio::test]
+async fn tag_span_records_geo_fields() {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[derive(Clone, Default)]
+    struct RecordedFields(Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl tracing::field::Visit for RecordedFields {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{}={:?}", field.name(), value));
+        }
+    }
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for RecordedFields {
+        fn on_record(
+            &self,
+            _id: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            values.record(&mut self.clone());
+        }
+    }
+
+    let recorded = RecordedFields::default();
+    let subscriber = tracing_subscriber::registry().with(recorded.clone());
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let geoip = GeoIpService::from_reader(test_city_reader());
+    let span = tracing::info_span!(
+        "request",
+        geo.country = tracing::field::Empty,
+        geo.city = tracing::field::Empty,
+        geo.lat = tracing::field::Empty,
+        geo.lon = tracing::field::Empty,
+    );
+
+    assert!(geoip.tag_span("9.9.9.9", &span).await.is_none());
+    assert!(recorded.0.lock().unwrap().is_empty());
+    assert!(geoip.tag_span("1.2.3.4", &span).await.is_some());
+    assert_eq!(
+        *recorded.0.lock().unwrap(),
+        [
+            "geo.country=\"Testland\"",
+            "geo.city=\"Test City\"",
+            "geo.lat=10.5",
+            "geo.lon=-20.25",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn lookup_with_context_nests_lookups_under_the_parent_span() {
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::registry::LookupSpan;
+
+    #[derive(Clone, Default)]
+    struct LookupParents(Arc<std::sync::Mutex<Vec<Option<String>>>>);
+
+    impl<S> tracing_subscriber::Layer<S> for LookupParents
+    where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _id: &tracing::span::Id,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            if attrs.metadata().name() != "geo_lookup" {
+                return;
+            }
+            let parent = attrs
+                .parent()
+                .and_then(|id| ctx.span(id))
+                .map(|span| span.name().to_string());
+            self.0.lock().unwrap().push(parent);
+        }
+    }
+
+    let parents = LookupParents::default();
+    let subscriber = tracing_subscriber::registry().with(parents.clone());
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let geoip = GeoIpService::from_reader(test_city_reader());
+    let request = tracing::info_span!("request");
+
+    let point = geoip.lookup_with_context("1.2.3.4", &request).await;
+    assert_eq!(point.and_then(|p| p.city).as_deref(), Some("Test City"));
+    assert!(geoip
+        .lookup_with_context("9.9.9.9", &request)
+        .await
+        .is_none());
+    // A disabled parent still resolves; the span just becomes a root.
+    assert!(geoip
+        .lookup_with_context("1.2.3.4", &tracing::Span::none())
+        .await
+        .is_some());
+
+    assert_eq!(
+        *parents.0.lock().unwrap(),
+        [
+            Some("request".to_string()),
+            Some("request".to_string()),
+            None
+        ]
+    );
+}
+
+#[tokio::test]
+async fn lookup_and_log_logs_each_result_and_its_source() {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let events = CapturedEvents::default();
+    let subscriber = tracing_subscriber::registry().with(events.clone());
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let geoip = GeoIpService::from_reader(test_city_reader());
+
+    let point = geoip.lookup_and_log("1.2.3.4").await.expect("point");
+    assert_eq!(point.city.as_deref(), Some("Test City"));
+    assert!(geoip.lookup_and_log("1.2.3.4").await.is_some());
+    assert!(geoip.lookup_and_log("9.9.9.9").await.is_none());
+
+    assert_eq!(
+        events.matching("geolocation lookup"),
+        [
+            "geolocation lookup ip=\"1.2.3.4\" city=\"Test City\" country=\"Testland\" \
+             lat=10.5 lon=-20.25 cache_hit=false",
+            "geolocation lookup ip=\"1.2.3.4\" city=\"Test City\" country=\"Testland\" \
+             lat=10.5 lon=-20.25 cache_hit=true",
+            "geolocation lookup ip=\"9.9.9.9\" cache_hit=false",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn stats_logging_reports_periodically_until_aborted() {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let events = CapturedEvents::default();
+    let subscriber = tracing_subscriber::registry().with(events.clone());
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let geoip = GeoIpService::from_reader(test_city_reader()).into_arc();
+    geoip.lookup("1.2.3.4").await;
+    geoip.lookup("1.2.3.4").await;
+
+    let task = GeoIpService::start_stats_logging(Arc::clone(&geoip), Duration::from_millis(20));
+    tokio::time::timeout(Duration::from_secs(1), async {
+        while events.matching("geolocation cache stats").is_empty() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .expect("stats logged");
+    assert_eq!(
+        events.matching("geolocation cache stats")[0],
+        "geolocation cache stats cache_size=1 cache_miss_rate=0.5 total_lookups=2"
+    );
+
+    task.abort();
+    assert!(task.await.expect_err("aborted").is_cancelled());
+    let logged = events.matching("geolocation cache stats").len();
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    assert_eq!(events.matching("geolocation cache stats").len(), logged);
+}
+
+#[tokio::test]
+async fn not_found_tracing_counts_misses_per_ip() {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let events = CapturedEvents::default();
+    let subscriber = tracing_subscriber::registry().with(events.clone());
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let geoip = GeoIpService::from_reader(test_city_reader()).with_not_found_tracing();
+
+    assert!(geoip.lookup("1.2.3.4").await.is_some());
+    assert!(events.matching("IP not found").is_empty());
+    assert!(geoip.lookup("9.9.9.9").await.is_none());
+    // Drop the cached miss so the next lookup reaches the database again.
+    geoip.evict_nulls().await;
+    assert!(geoip.lookup("9.9.9.9").await.is_none());
+    assert_eq!(
+        events.matching("IP not found"),
+        [
+            "IP not found in MaxMind database ip=9.9.9.9 misses=1",
+            "IP not found in MaxMind database ip=9.9.9.9 misses=2",
+        ]
+    );
+
+    let untraced = GeoIpService::from_reader(test_city_reader());
+    assert!(untraced.lookup("9.9.9.8").await.is_none());
+    assert_eq!(events.matching("IP not found").len(), 2);
+}
+
+#[tokio::test]
+async fn lookup_json_is_self_describing() {
+    let geoip = GeoIpService::from_reader(test_city_reader());
+
+    let found = geoip.lookup_json("1.2.3.4").await;
+    assert_eq!(found["ip"], "1.2.3.4");
+    assert_eq!(found["city"], "Test City");
+    assert_eq!(found["latitude"], 10.5);
+    assert_eq!(geoip.lookup_json("9.9.9.9").await, serde_json::Value::Null);
+}
+
+#[tokio::test]
+async fn parallel_lookup_preserves_input_order() {
+    let geoip = GeoIpService::from_reader(test_city_reader()).with_max_concurrent_lookups(1);
+    let ips = ["9.9.9.9", "1.2.3.4", "", "1.2.3.5", "bogus"];
+
+    let results = geoip.parallel_lookup(&ips, 2).await;
+    let found: Vec<bool> = results.iter().map(Option::is_some).collect();
+    assert_eq!(found, [false, true, false, true, false]);
+}
+
+#[tokio::test]
+async fn tag_request_inserts_geo_point_extension() {
+    let geoip = GeoIpService::from_reader(test_city_reader());
+
+    let mut forwarded = Request::builder()
+        .header("x-forwarded-for", "1.2.3.4, 10.0.0.1")
+        .body(())
+        .expect("request");
+    geoip.tag_request(&mut forwarded).await;
+    let point = forwarded.extensions().get::<GeoPoint>().expect("tagged");
+    assert_eq!(point.city.as_deref(), Some("Test City"));
+
+    let mut peer = Request::builder().body(()).expect("request");
+    peer.extensions_mut()
+        .insert(axum::extract::ConnectInfo(std::net::SocketAddr::from((
+            [1, 2, 3, 9],
+            443,
+        ))));
+    geoip.tag_request(&mut peer).await;
+    assert!(peer.extensions().get::<GeoPoint>().is_some());
+
+    let mut real_ip_wins = Request::builder()
+        .header("x-real-ip", "9.9.9.9")
+        .header("x-forwarded-for", "1.2.3.4")
+        .body(())
+        .expect("request");
+    geoip.tag_request(&mut real_ip_wins).await;
+    assert!(real_ip_wins.extensions().get::<GeoPoint>().is_none());
+}
+
+#[tokio::test]
+async fn lookup_range_resolves_each_address() {
+    let geoip = GeoIpService::from_reader(test_city_reader());
+    let start: std::net::IpAddr = "1.2.3.254".parse().expect("ip");
+    let end: std::net::IpAddr = "1.2.4.1".parse().expect("ip");
+
+    let results = geoip.lookup_range(start, end).await.expect("range");
+    assert_eq!(results.len(), 4);
+    assert_eq!(results[0].0, start);
+    assert!(results[1].1.is_some());
+    assert!(results[2].1.is_none());
+    assert_eq!(results[3].0, end);
+
+    assert!(geoip.lookup_range(end, start).await.is_err());
+    let v6: std::net::IpAddr = "::1".parse().expect("ip");
+    assert!(geoip.lookup_range(start, v6).await.is_err());
+
+    let noop = GeoIpService::new_noop();
+    let wide = noop
+        .lookup_range(
+            "10.0.0.0".parse().expect("ip"),
+            "10.255.255.255".parse().expect("ip"),
+        )
+        .await
+        .expect("capped range");
+    assert_eq!(wide.len(), crate::geo::MAX_LOOKUP_RANGE);
+}
+
+#[tokio::test]
+async fn geo_tagged_pool_routes_to_nearest_member() {
+    let mut cache_map = std::collections::HashMap::new();
+    cache_map.insert(
+        "1.1.1.1".to_string(),
+        Some(test_point(51.5, -0.1, Some("London"), None)),
+    );
+    cache_map.insert(
+        "2.2.2.2".to_string(),
+        Some(test_point(40.7, -74.0, Some("New York"), None)),
+    );
+    let geoip = GeoIpService::from_static(cache_map);
+    let pool = GeoTaggedPool::from_pool(
+        vec![
+            ("1.1.1.1:8000".to_string(), "london"),
+            ("2.2.2.2".to_string(), "new-york"),
+            ("unresolvable".to_string(), "unknown"),
+        ],
+        &geoip,
+    )
+    .await;
+
+    assert_eq!(pool.members().len(), 3);
+    let paris = test_point(48.85, 2.35, Some("Paris"), None);
+    assert_eq!(pool.nearest_to(&paris), Some(&"london"));
+    let boston = test_point(42.36, -71.06, Some("Boston"), None);
+    assert_eq!(pool.nearest_to(&boston), Some(&"new-york"));
+}
+
+#[tokio::test]
+async fn lookup_merges_anonymous_ip_flags() {
+    let anon = build_test_mmdb(
+        "GeoIP2-Anonymous-IP",
+        &[(
+            "1.2.3.4/32",
+            serde_json::json!({ "is_anonymous": true, "is_anonymous_vpn": true }),
+        )],
+    );
+    let geoip = GeoIpService::from_reader(test_city_reader())
+        .with_anonymous_ip_reader(maxminddb::Reader::from_source(anon).expect("anon mmdb"));
+
+    let flagged = geoip.lookup("1.2.3.4").await.expect("flagged lookup");
+    assert_eq!(flagged.is_anonymous, Some(true));
+    assert_eq!(flagged.is_vpn, Some(true));
+    assert_eq!(flagged.is_tor, Some(false));
+
+    let clean = geoip.lookup("1.2.3.5").await.expect("clean lookup");
+    assert_eq!(clean.is_anonymous, Some(false));
+    assert_eq!(clean.is_vpn, Some(false));
+}
+
+#[tokio::test]
+async fn annotate_cloud_ranges_tags_providers_without_touching_lookups() {
+    let geoip = GeoIpService::from_reader(test_city_reader());
+    assert!(geoip.lookup("9.9.9.1").await.is_none());
+
+    let ranges = [
+        ("9.9.9.0/29".parse().expect("net"), "AWS"),
+        ("9.9.9.0/30".parse().expect("net"), "AWS Lambda"),
+        ("1.2.3.0/24".parse().expect("net"), "GCP"),
+        ("2001:db8::/127".parse().expect("net"), "GCP"),
+    ];
+    assert_eq!(geoip.annotate_cloud_ranges(&ranges), 4);
+    assert_eq!(geoip.annotate_cloud_ranges(&ranges), 0);
+
+    assert_eq!(
+        geoip.cloud_provider("9.9.9.1").as_deref(),
+        Some("AWS Lambda")
+    );
+    assert_eq!(geoip.cloud_provider("9.9.9.5").as_deref(), Some("AWS"));
+    assert_eq!(
+        geoip.cloud_provider("::ffff:9.9.9.5").as_deref(),
+        Some("AWS")
+    );
+    assert_eq!(geoip.cloud_provider("2001:db8::1").as_deref(), Some("GCP"));
+    assert!(geoip.cloud_provider("9.9.9.8").is_none());
+    assert!(geoip.cloud_provider("bogus").is_none());
+
+    // Lookups and cache stats still only reflect the database.
+    assert!(geoip.lookup("9.9.9.1").await.is_none());
+    let point = geoip.lookup("1.2.3.4").await.expect("point");
+    assert_eq!(point.latitude, 10.5);
+    assert_eq!(point.country(), Some("Testland"));
+    assert_eq!(geoip.lookup_all_cached().await.len(), 1);
+}
+
+#[tokio::test]
+async fn lookup_and_publish_sends_only_fresh_points() {
+    let (tx, mut rx) = tokio::sync::broadcast::channel(8);
+    let geoip = GeoIpService::from_reader(test_city_reader()).with_event_channel(tx);
+
+    assert!(geoip.lookup_and_publish("1.2.3.4").await.is_some());
+    assert!(geoip.lookup_and_publish("1.2.3.4").await.is_some());
+    assert!(geoip.lookup_and_publish("9.9.9.9").await.is_none());
+
+    let event = rx.try_recv().expect("event for fresh lookup");
+    assert_eq!(event.ip, "1.2.3.4");
+    assert_eq!(event.point.latitude, 10.5);
+    assert!(rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn circuit_breaker_fast_fails_after_reader_errors_then_probes() {
+    // A string where the City record expects a map makes decoding fail.
+    let bytes = build_test_mmdb(
+        "GeoIP2-City",
+        &[
+            ("1.0.0.0/8", serde_json::json!({ "location": "corrupt" })),
+            (
+                "2.0.0.0/8",
+                serde_json::json!({ "location": { "latitude": 1.5, "longitude": 2.5 } }),
+            ),
+        ],
+    );
+    let geoip = GeoIpService::from_reader(maxminddb::Reader::from_source(bytes).expect("mmdb"))
+        .with_circuit_breaker(2, Duration::from_millis(50));
+    assert!(geoip.healthy());
+
+    assert!(geoip.lookup_with_circuit_breaker("1.0.0.1").await.is_none());
+    assert!(geoip.lookup_with_circuit_breaker("1.0.0.2").await.is_none());
+    assert!(!geoip.healthy());
+    // Open: a healthy IP is skipped and left uncached.
+    assert!(geoip.lookup_with_circuit_breaker("2.0.0.1").await.is_none());
+    assert!(geoip.lookup_all_cached().await.is_empty());
+    // Cache hits are still served while open.
+    assert!(geoip.lookup_with_circuit_breaker("1.0.0.1").await.is_none());
+
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    assert!(geoip.lookup_with_circuit_breaker("1.0.0.3").await.is_none());
+    assert!(geoip.lookup_with_circuit_breaker("2.0.0.1").await.is_none());
+
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    let probe = geoip.lookup_with_circuit_breaker("2.0.0.1").await;
+    assert_eq!(probe.map(|p| p.latitude), Some(1.5));
+    let closed = geoip.lookup_with_circuit_breaker("2.0.0.2").await;
+    assert_eq!(closed.map(|p| p.latitude), Some(1.5));
+    assert!(geoip.healthy());
+}
+
+#[tokio::test]
+async fn circuit_breaker_admits_one_probe_while_half_open() {
+    let bytes = build_test_mmdb(
+        "GeoIP2-City",
+        &[
+            ("1.0.0.0/8", serde_json::json!({ "location": "corrupt" })),
+            (
+                "2.0.0.0/8",
+                serde_json::json!({ "location": { "latitude": 1.5, "longitude": 2.5 } }),
+            ),
+        ],
+    );
+    let limit = Arc::new(tokio::sync::Semaphore::new(1));
+    let geoip = GeoIpService::from_reader(maxminddb::Reader::from_source(bytes).expect("mmdb"))
+        .with_lookup_limit(Arc::clone(&limit))
+        .with_circuit_breaker(1, Duration::from_millis(50));
+    assert!(geoip.lookup_with_circuit_breaker("2.0.0.1").await.is_some());
+    assert!(geoip.lookup_with_circuit_breaker("1.0.0.1").await.is_none());
+    tokio::time::sleep(Duration::from_millis(60)).await;
+
+    // The probe is admitted, then parks on the lookup permit held here.
+    let held = limit.acquire().await.expect("permit");
+    let probe = tokio::spawn({
+        let geoip = geoip.clone();
+        async move { geoip.lookup_with_circuit_breaker("2.0.0.2").await }
+    });
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert!(!probe.is_finished());
+
+    // Neither a cache hit nor a concurrent caller may release the probe or
+    // become a second one; the caller is turned away without waiting.
+    assert!(geoip.lookup_with_circuit_breaker("2.0.0.1").await.is_some());
+    let second = tokio::time::timeout(
+        Duration::from_millis(200),
+        geoip.lookup_with_circuit_breaker("2.0.0.3"),
+    )
+    .await
+    .expect("second caller rejected while the probe is in flight");
+    assert!(second.is_none());
+
+    drop(held);
+    let probed = probe.await.expect("probe task");
+    assert_eq!(probed.map(|p| p.latitude), Some(1.5));
+    let closed = geoip.lookup_with_circuit_breaker("2.0.0.3").await;
+    assert_eq!(closed.map(|p| p.latitude), Some(1.5));
+}
+
+#[tokio::test]
+async fn lookup_with_backoff_retries_only_reader_errors() {
+    let bytes = build_test_mmdb(
+        "GeoIP2-City",
+        &[("1.0.0.0/8", serde_json::json!({ "location": "corrupt" }))],
+    );
+    let geoip = GeoIpService::from_reader(maxminddb::Reader::from_source(bytes).expect("mmdb"));
+
+    let started = std::time::Instant::now();
+    let corrupt = geoip
+        .lookup_with_backoff("1.0.0.1", 2, Duration::from_millis(10))
+        .await;
+    assert!(corrupt.is_none());
+    assert_eq!(geoip.total_lookup_count(), 3);
+    assert!(started.elapsed() >= Duration::from_millis(30));
+
+    let missing = geoip
+        .lookup_with_backoff("9.9.9.9", 2, Duration::from_millis(10))
+        .await;
+    assert!(missing.is_none());
+    assert_eq!(geoip.total_lookup_count(), 4);
+}
+
+#[tokio::test]
+async fn lookup_debug_describes_each_step() {
+    let geoip = GeoIpService::from_reader(test_city_reader());
+
+    let first = geoip.lookup_debug("1.2.3.4").await;
+    assert_eq!(
+        first.lines().collect::<Vec<_>>(),
+        [
+            "Parse: OK",
+            "Cache: MISS",
+            "Reader: present",
+            "MaxMind result: found",
+            "GeoPoint: (lat=10.5, lon=-20.25, city=Test City, country=Testland)",
+        ]
+    );
+    let second = geoip.lookup_debug("1.2.3.4").await;
+    assert!(second.contains("Cache: HIT"));
+    assert!(!second.contains("Reader:"));
+
+    let miss = geoip.lookup_debug("9.9.9.9").await;
+    assert!(miss.contains("MaxMind result: not found"));
+
+    let invalid = geoip.lookup_debug("nope").await;
+    assert!(invalid.starts_with("Parse: FAILED"));
+    assert!(invalid.contains("MaxMind result: skipped (parse failed)"));
+    assert!(invalid.ends_with("GeoPoint: none"));
+
+    let noop = GeoIpService::new_noop().lookup_debug("1.2.3.4").await;
+    assert!(noop.contains("Reader: absent"));
+    assert!(noop.contains("MaxMind result: skipped (no reader)"));
+}
+
+#[tokio::test]
+async fn lookup_debug_reports_timeouts() {
+    let limit = Arc::new(tokio::sync::Semaphore::new(1));
+    let geoip = GeoIpService::from_reader(test_city_reader())
+        .with_lookup_limit(Arc::clone(&limit))
+        .with_lookup_timeout(Duration::from_millis(30));
+
+    let _held = limit.acquire().await.expect("permit");
+    let (result, source) = geoip.lookup_with_metadata("1.2.3.4").await;
+    assert!(result.is_none());
+    assert_eq!(source, GeoSource::TimedOut);
+    let debug = geoip.lookup_debug("1.2.3.4").await;
+    assert!(debug.contains("MaxMind result: timed out"));
+    assert!(!debug.contains("not found"));
+}
+
+#[tokio::test]
+async fn tenant_overrides_apply_only_to_their_tenant() {
+    let geoip = GeoIpService::from_reader(test_city_reader());
+    let paris = test_point(48.8566, 2.3522, Some("Paris"), Some("France"));
+    geoip.set_tenant_override("acme", "1.2.3.4", paris).await;
+
+    let acme = geoip.lookup_for_tenant("acme", "1.2.3.4").await;
+    assert_eq!(acme.and_then(|p| p.city), Some("Paris".to_string()));
+    let other = geoip.lookup_for_tenant("globex", "1.2.3.4").await;
+    assert_eq!(other.map(|p| p.latitude), Some(10.5));
+    assert_eq!(
+        geoip.lookup("1.2.3.4").await.map(|p| p.latitude),
+        Some(10.5)
+    );
+}
+
+#[tokio::test]
+async fn lookup_enriched_merges_city_asn_and_anonymous_ip() {
+    let asn = build_test_mmdb(
+        "GeoLite2-ASN",
+        &[(
+            "1.2.3.0/24",
+            serde_json::json!({
+                "autonomous_system_number": 64512,
+                "autonomous_system_organization": "Example Net",
+            }),
+        )],
+    );
+    let anon = build_test_mmdb(
+        "GeoIP2-Anonymous-IP",
+        &[(
+            "1.2.3.4/32",
+            serde_json::json!({ "is_tor_exit_node": true }),
+        )],
+    );
+    let geoip = GeoIpService::from_reader(test_city_reader())
+        .with_asn_reader(maxminddb::Reader::from_source(asn).expect("asn mmdb"))
+        .with_anonymous_ip_reader(maxminddb::Reader::from_source(anon).expect("anon mmdb"));
+
+    let enriched = geoip.lookup_enriched("1.2.3.4").await;
+    assert_eq!(enriched.geo.map(|p| p.latitude), Some(10.5));
+    assert_eq!(
+        enriched.asn,
+        Some(AsnInfo {
+            number: 64512,
+            organization: Some("Example Net".to_string()),
+        })
+    );
+    assert!(enriched.anon.expect("anon info").is_tor);
+
+    assert!(enriched.registered_domain.is_none());
+
+    let unknown = geoip.lookup_enriched("9.9.9.9").await;
+    assert!(unknown.geo.is_none() && unknown.asn.is_none());
+    assert!(GeoIpService::new_noop().lookup_asn("1.2.3.4").is_none());
+}
+
+#[tokio::test]
+async fn domain_database_reports_registered_domain() {
+    let domain = build_test_mmdb(
+        "GeoIP2-Domain",
+        &[("1.2.3.0/24", serde_json::json!({ "domain": "example.com" }))],
+    );
+    let geoip = GeoIpService::from_reader(test_city_reader())
+        .with_domain_reader(maxminddb::Reader::from_source(domain).expect("domain mmdb"));
+
+    assert_eq!(
+        geoip.lookup_registered_domain("::ffff:1.2.3.4").as_deref(),
+        Some("example.com")
+    );
+    assert!(geoip.lookup_registered_domain("9.9.9.9").is_none());
+    let enriched = geoip.lookup_enriched("1.2.3.4").await;
+    assert_eq!(enriched.registered_domain.as_deref(), Some("example.com"));
+}
+
+#[test]
+fn anonymous_ip_database_serves_anonymous_ip_info() {
+    let anon = build_test_mmdb(
+        "GeoIP2-Anonymous-IP",
+        &[(
+            "1.2.3.4/32",
+            serde_json::json!({ "is_anonymous": true, "is_public_proxy": true }),
+        )],
+    );
+    let geoip = GeoIpService::from_anonymous_ip_reader(
+        maxminddb::Reader::from_source(anon).expect("anon mmdb"),
+    );
+
+    assert_eq!(
+        geoip.lookup_anonymous_ip("::ffff:1.2.3.4"),
+        Some(AnonymousIpInfo {
+            is_anonymous: true,
+            is_vpn: false,
+            is_tor: false,
+            is_proxy: true,
+        })
+    );
+    assert_eq!(
+        geoip.lookup_anonymous_ip("1.2.3.5"),
+        Some(AnonymousIpInfo::default())
+    );
+    assert_eq!(geoip.lookup_anonymous_ip("not-an-ip"), None);
+}
+
+#[tokio::test]
+async fn multi_edition_lookup_prefers_earlier_services() {
+    let mut premium = std::collections::HashMap::new();
+    premium.insert(
+        "1.2.3.4".to_string(),
+        Some(test_point(1.0, 1.0, Some("Premium"), None)),
+    );
+    premium.insert("5.6.7.8".to_string(), None);
+    let mut lite = std::collections::HashMap::new();
+    lite.insert(
+        "1.2.3.4".to_string(),
+        Some(test_point(2.0, 2.0, Some("Lite"), None)),
+    );
+    lite.insert(
+        "5.6.7.8".to_string(),
+        Some(test_point(3.0, 3.0, Some("Lite only"), None)),
+    );
+    let geoip = MultiEditionGeoIpService::new(vec![
+        GeoIpService::from_static(premium),
+        GeoIpService::from_static(lite),
+    ]);
+
+    let first = geoip.lookup("1.2.3.4").await.expect("premium hit");
+    assert_eq!(first.city.as_deref(), Some("Premium"));
+    let fallback = geoip.lookup("5.6.7.8").await.expect("lite hit");
+    assert_eq!(fallback.city.as_deref(), Some("Lite only"));
+    assert!(geoip.lookup("9.9.9.9").await.is_none());
+}
+
+#[tokio::test]
+async fn from_url_opens_cached_database_without_downloading() {
+    let cache_dir = std::env::temp_dir().join(format!("leader-stream-geo-{}", std::process::id()));
+    std::fs::create_dir_all(&cache_dir).expect("cache dir");
+    let bytes = build_test_mmdb(
+        "GeoIP2-City",
+        &[(
+            "1.2.3.0/24",
+            serde_json::json!({ "location": { "latitude": 1.5, "longitude": 2.5 } }),
+        )],
+    );
+    std::fs::write(cache_dir.join("GeoIP2-City.mmdb"), bytes).expect("write mmdb");
+
+    let geoip = GeoIpService::from_url("http://127.0.0.1:1/db/GeoIP2-City.mmdb.gz", &cache_dir)
+        .await
+        .expect("cached database");
+    let point = geoip.lookup("1.2.3.4").await.expect("cached lookup");
+    assert_eq!(point.latitude, 1.5);
+
+    assert!(GeoIpService::from_url("http://127.0.0.1:1/", &cache_dir)
+        .await
+        .is_err());
+    let _ = std::fs::remove_dir_all(&cache_dir);
+}
+
+#[tokio::test]
+async fn fetch_and_write_rejects_oversized_downloads() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+    let app = axum::Router::new().route(
+        "/GeoIP2-City.mmdb",
+        axum::routing::get(|| async { vec![0u8; 4096] }),
+    );
+    tokio::spawn(async move {
+        let _ = axum::serve(listener, app).await;
+    });
+
+    let target = std::env::temp_dir().join(format!(
+        "leader-stream-oversized-{}.mmdb",
+        std::process::id()
+    ));
+    let url = format!("http://{}/GeoIP2-City.mmdb", addr);
+    let err = fetch_and_write(&reqwest::Client::new(), &url, &target, true, 1024, None)
+        .await
+        .expect_err("oversized download");
+    assert!(err.to_string().contains("byte limit"));
+    assert!(!target.exists());
+}
+
+#[tokio::test]
+async fn fetch_and_write_limits_decompressed_size() {
+    use std::io::Write;
+
+    // 1 MiB of zeros compresses to about a kilobyte.
+    let inflated = vec![0u8; 1024 * 1024];
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    gz.write_all(&inflated).expect("compress");
+    let gz = gz.finish().expect("finish gzip");
+    let mut tar = tar::Builder::new(flate2::write::GzEncoder::new(
+        Vec::new(),
+        flate2::Compression::best(),
+    ));
+    let mut header = tar::Header::new_gnu();
+    header.set_size(inflated.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    tar.append_data(
+        &mut header,
+        "GeoIP2-City/GeoIP2-City.mmdb",
+        inflated.as_slice(),
+    )
+    .expect("append entry");
+    let tar_gz = tar
+        .into_inner()
+        .expect("finish tar")
+        .finish()
+        .expect("finish gzip");
+    let limit = 64 * 1024;
+    assert!(gz.len() < limit && tar_gz.len() < limit);
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+    let app = axum::Router::new()
+        .route(
+            "/GeoIP2-City.mmdb.gz",
+            axum::routing::get(move || async move { gz }),
+        )
+        .route(
+            "/GeoIP2-City.tar.gz",
+            axum::routing::get(move || async move { tar_gz }),
+        );
+    tokio::spawn(async move {
+        let _ = axum::serve(listener, app).await;
+    });
+
+    let target = std::env::temp_dir().join(format!(
+        "leader-stream-gzip-bomb-{}.mmdb",
+        std::process::id()
+    ));
+    let client = reqwest::Client::new();
+    for (path, raw_mmdb) in [("GeoIP2-City.mmdb.gz", true), ("GeoIP2-City.tar.gz", false)] {
+        let url = format!("http://{}/{}", addr, path);
+        let err = fetch_and_write(&client, &url, &target, raw_mmdb, limit as u64, None)
+            .await
+            .expect_err("inflated past the limit");
+        assert!(
+            format!("{err:#}").contains("decompressed database exceeded"),
+            "{err:#}"
+        );
+        assert!(!target.exists());
+    }
+}
+
+#[tokio::test]
+async fn fetch_and_write_sends_mirror_basic_auth() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+    let app = axum::Router::new().route(
+        "/GeoIP2-City.mmdb",
+        axum::routing::get(|headers: axum::http::HeaderMap| async move {
+            // "mirror:secret" in base64.
+            if headers
+                .get(header::AUTHORIZATION)
+                .map(|value| value.as_bytes())
+                == Some(b"Basic bWlycm9yOnNlY3JldA==".as_slice())
+            {
+                Ok(build_test_mmdb("GeoIP2-City", &[]))
+            } else {
+                Err(StatusCode::UNAUTHORIZED)
+            }
+        }),
+    );
+    tokio::spawn(async move {
+        let _ = axum::serve(listener, app).await;
+    });
+
+    let target =
+        std::env::temp_dir().join(format!("leader-stream-auth-{}.mmdb", std::process::id()));
+    let url = format!("http://{}/GeoIP2-City.mmdb", addr);
+    let client = reqwest::Client::new();
+    assert!(
+        fetch_and_write(&client, &url, &target, true, 1024 * 1024, None)
+            .await
+            .is_err()
+    );
+    fetch_and_write(
+        &client,
+        &url,
+        &target,
+        true,
+        1024 * 1024,
+        Some(("mirror", "secret")),
+    )
+    .await
+    .expect("authenticated download");
+    assert!(target.exists());
+    let _ = std::fs::remove_file(&target);
+}
+
+#[tokio::test]
+async fn download_database_sends_mirror_auth_only_to_configured_mirrors() {
+    use std::sync::Mutex;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+    // Path of each request and whether it carried an Authorization header.
+    type Seen = Arc<Mutex<Vec<(String, bool)>>>;
+    let seen: Seen = Arc::default();
+    let app = axum::Router::new()
+        .route(
+            "/*path",
+            axum::routing::get(
+                |axum::extract::State(seen): axum::extract::State<Seen>,
+                 uri: axum::http::Uri,
+                 headers: axum::http::HeaderMap| async move {
+                    seen.lock().unwrap().push((
+                        uri.path().to_string(),
+                        headers.contains_key(header::AUTHORIZATION),
+                    ));
+                    build_test_mmdb("GeoIP2-City", &[])
+                },
+            ),
+        )
+        .with_state(Arc::clone(&seen));
+    tokio::spawn(async move {
+        let _ = axum::serve(listener, app).await;
+    });
+
+    let target = std::env::temp_dir().join(format!(
+        "leader-stream-download-auth-{}.mmdb",
+        std::process::id()
+    ));
+    let default_url = format!("http://{}/default/GeoLite2-City-Test.mmdb", addr);
+    let config = Config {
+        maxmind_download_basic_auth_user: Some("mirror".to_string()),
+        maxmind_download_basic_auth_password: Some("secret".to_string()),
+        ..test_config()
+    };
+
+    // No mirror configured: the built-in default must not see credentials.
+    download_database(&config, &target, &default_url)
+        .await
+        .expect("default download");
+    let fallback = Config {
+        maxmind_fallback_url: Some(format!("http://{}/fallback/GeoIP2-City.mmdb", addr)),
+        ..config.clone()
+    };
+    download_database(&fallback, &target, &default_url)
+        .await
+        .expect("fallback download");
+    let mirror = Config {
+        maxmind_db_download_url: Some(format!("http://{}/mirror/GeoIP2-City.mmdb", addr)),
+        ..config
+    };
+    download_database(&mirror, &target, &default_url)
+        .await
+        .expect("mirror download");
+
+    assert_eq!(
+        *seen.lock().unwrap(),
+        [
+            ("/default/GeoLite2-City-Test.mmdb".to_string(), false),
+            ("/fallback/GeoIP2-City.mmdb".to_string(), true),
+            ("/mirror/GeoIP2-City.mmdb".to_string(), true),
+        ]
+    );
+    let _ = std::fs::remove_file(&target);
+}
+
+#[tokio::test]
+async fn builder_awaits_into_loaded_service() {
+    let path =
+        std::env::temp_dir().join(format!("leader-stream-builder-{}.mmdb", std::process::id()));
+    let bytes = build_test_mmdb(
+        "GeoIP2-City",
+        &[(
+            "1.2.3.0/24",
+            serde_json::json!({ "location": { "latitude": 1.5, "longitude": 2.5 } }),
+        )],
+    );
+    std::fs::write(&path, bytes).expect("write mmdb");
+    let config = Config {
+        maxmind_db_path: path.to_string_lossy().into_owned(),
+        ..test_config()
+    };
+
+    let geoip = GeoIpServiceBuilder::new(config).await.expect("load geoip");
+    assert_eq!(geoip.lookup("1.2.3.4").await.map(|p| p.latitude), Some(1.5));
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn builder_rejects_invalid_config_before_io() {
+    let dir = std::env::temp_dir().join(format!(
+        "leader-stream-builder-invalid-{}",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    let config = |update: fn(&mut Config)| {
+        let mut config = Config {
+            maxmind_db_path: dir
+                .join("GeoLite2-City.mmdb")
+                .to_string_lossy()
+                .into_owned(),
+            ..test_config()
+        };
+        update(&mut config);
+        config
+    };
+
+    let invalid: [fn(&mut Config); 4] = [
+        |config| config.maxmind_download_basic_auth_user = Some("user".to_string()),
+        |config| config.maxmind_db_download_url = Some("ftp://example.com/db.mmdb".to_string()),
+        |config| config.maxmind_max_download_bytes = 0,
+        |config| config.maxmind_lookup_timeout_ms = Some(0),
+    ];
+    for update in invalid {
+        let Err(err) = GeoIpServiceBuilder::new(config(update)).build().await else {
+            panic!("invalid config was accepted");
+        };
+        assert!(err.to_string().contains("MAXMIND_"), "{err:#}");
+    }
+    // Loading would have created the database directory.
+    assert!(!dir.exists());
+}
+
+#[tokio::test]
+async fn offline_load_requires_existing_database() {
+    let dir = std::env::temp_dir().join(format!("leader-stream-offline-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let path = dir.join("GeoLite2-City.mmdb");
+    let config = Config {
+        maxmind_db_path: path.to_string_lossy().into_owned(),
+        maxmind_offline: true,
+        ..test_config()
+    };
+
+    let Err(err) = load_geoip(&config).await else {
+        panic!("offline load without a database succeeded");
+    };
+    assert!(err.to_string().contains("offline mode"), "{err:#}");
+    // No download was attempted, so the directory was never created.
+    assert!(!dir.exists());
+
+    std::fs::create_dir_all(&dir).expect("create dir");
+    std::fs::write(
+        &path,
+        build_test_mmdb(
+            "GeoIP2-City",
+            &[(
+                "1.2.3.0/24",
+                serde_json::json!({ "location": { "latitude": 1.5, "longitude": 2.5 } }),
+            )],
+        ),
+    )
+    .expect("write mmdb");
+    let geoip = load_geoip_offline(&config).await.expect("offline load");
+    assert_eq!(geoip.lookup("1.2.3.4").await.map(|p| p.latitude), Some(1.5));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn new_lazy_loads_database_on_first_lookup() {
+    let path = std::env::temp_dir().join(format!("leader-stream-lazy-{}.mmdb", std::process::id()));
+    let bytes = build_test_mmdb(
+        "GeoIP2-City",
+        &[(
+            "1.2.3.0/24",
+            serde_json::json!({ "location": { "latitude": 1.5, "longitude": 2.5 } }),
+        )],
+    );
+    std::fs::write(&path, bytes).expect("write mmdb");
+    let config = Config {
+        maxmind_db_path: path.to_string_lossy().into_owned(),
+        ..test_config()
+    };
+
+    let geoip = GeoIpService::new_lazy(Arc::new(config));
+    assert!(geoip.database_info().is_none());
+    assert_eq!(geoip.lookup("1.2.3.4").await.map(|p| p.latitude), Some(1.5));
+    assert!(geoip.database_info().is_some());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn new_lazy_keeps_loading_past_lookup_timeouts() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+    let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let bytes = build_test_mmdb(
+        "GeoIP2-City",
+        &[(
+            "1.2.3.0/24",
+            serde_json::json!({ "location": { "latitude": 1.5, "longitude": 2.5 } }),
+        )],
+    );
+    let app = axum::Router::new().route(
+        "/GeoIP2-City.mmdb",
+        axum::routing::get({
+            let requests = Arc::clone(&requests);
+            move || async move {
+                requests.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                bytes
+            }
+        }),
+    );
+    tokio::spawn(async move {
+        let _ = axum::serve(listener, app).await;
+    });
+
+    let path = std::env::temp_dir().join(format!(
+        "leader-stream-lazy-timeout-{}.mmdb",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let config = Config {
+        maxmind_db_path: path.to_string_lossy().into_owned(),
+        maxmind_db_download_url: Some(format!("http://{}/GeoIP2-City.mmdb", addr)),
+        maxmind_lookup_timeout_ms: Some(20),
+        ..test_config()
+    };
+
+    let geoip = GeoIpService::new_lazy(Arc::new(config));
+    // Each lookup gives up before the download finishes, without caching
+    // the miss or restarting the load.
+    for _ in 0..3 {
+        assert_eq!(
+            geoip.lookup_with_metadata("1.2.3.4").await.1,
+            GeoSource::TimedOut
+        );
+    }
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while geoip.database_info().is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("database loaded in the background");
+    assert_eq!(geoip.lookup("1.2.3.4").await.map(|p| p.latitude), Some(1.5));
+    assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn swap_reader_replaces_database_and_drops_cached_misses() {
+    let path = std::env::temp_dir().join(format!("leader-stream-swap-{}.mmdb", std::process::id()));
+    let bytes = build_test_mmdb(
+        "GeoIP2-City",
+        &[(
+            "5.6.7.0/24",
+            serde_json::json!({ "location": { "latitude": 3.5, "longitude": 4.5 } }),
+        )],
+    );
+    std::fs::write(&path, bytes).expect("write mmdb");
+    let refreshes = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let geoip = GeoIpService::from_reader(test_city_reader()).with_reader_refresh_callback({
+        let refreshes = Arc::clone(&refreshes);
+        Arc::new(move || {
+            refreshes.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        })
+    });
+    let handle = geoip.clone();
+    let mut updates = geoip.watch_updates();
+    assert!(geoip.lookup("1.2.3.4").await.is_some());
+    assert!(geoip.lookup("5.6.7.8").await.is_none());
+
+    let info = geoip.swap_reader(&path).await.expect("swap reader");
+    assert_eq!(info.database_type, "GeoIP2-City");
+    let event = updates.recv().await.expect("update event");
+    assert_eq!(event.new_node_count, info.node_count);
+    assert!(event.reload_time <= std::time::SystemTime::now());
+    assert_eq!(refreshes.load(std::sync::atomic::Ordering::SeqCst), 1);
+    assert_eq!(
+        handle.lookup("5.6.7.8").await.map(|p| p.latitude),
+        Some(3.5)
+    );
+    // Cached points survive the swap even though the new database lacks them.
+    assert!(handle.lookup("1.2.3.4").await.is_some());
+
+    let anon_path = std::env::temp_dir().join(format!(
+        "leader-stream-swap-anon-{}.mmdb",
+        std::process::id()
+    ));
+    std::fs::write(&anon_path, build_test_mmdb("GeoIP2-Anonymous-IP", &[])).expect("write mmdb");
+    assert!(geoip.swap_reader(&anon_path).await.is_err());
+    assert_eq!(
+        geoip.database_info().map(|info| info.node_count),
+        Some(info.node_count)
+    );
+    // A rejected swap does not fire the callback; the next good one does.
+    assert_eq!(refreshes.load(std::sync::atomic::Ordering::SeqCst), 1);
+    geoip.swap_reader(&path).await.expect("swap reader again");
+    assert_eq!(refreshes.load(std::sync::atomic::Ordering::SeqCst), 2);
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&anon_path);
+}
+
+#[tokio::test]
+async fn subscribe_receives_point_changes_after_swap_reader() {
+    let path = std::env::temp_dir().join(format!(
+        "leader-stream-subscribe-{}.mmdb",
+        std::process::id()
+    ));
+    let bytes = build_test_mmdb(
+        "GeoIP2-City",
+        &[(
+            "1.2.3.0/24",
+            serde_json::json!({
+                "city": { "names": { "en": "Moved City" } },
+                "location": { "latitude": 3.5, "longitude": 4.5 },
+            }),
+        )],
+    );
+    std::fs::write(&path, bytes).expect("write mmdb");
+    let geoip = GeoIpService::from_reader(test_city_reader());
+    let mut moved = geoip.subscribe("1.2.3.4".to_string());
+    let mut missing = geoip.subscribe("9.9.9.9".to_string());
+    assert_eq!(
+        moved
+            .borrow_and_update()
+            .as_ref()
+            .and_then(|p| p.city.clone()),
+        Some("Test City".to_string())
+    );
+
+    geoip.swap_reader(&path).await.expect("swap reader");
+    tokio::time::timeout(Duration::from_secs(1), moved.changed())
+        .await
+        .expect("update before timeout")
+        .expect("sender alive");
+    assert_eq!(
+        moved
+            .borrow()
+            .as_ref()
+            .map(|p| (p.latitude, p.city.clone())),
+        Some((3.5, Some("Moved City".to_string())))
+    );
+    // Unchanged answers do not wake subscribers.
+    assert!(!missing.has_changed().expect("sender alive"));
+    assert!(missing.borrow_and_update().is_none());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn compute_stats_summarizes_cache_contents() {
+    let mut cache_map = std::collections::HashMap::new();
+    cache_map.insert(
+        "1.1.1.1".to_string(),
+        Some(test_point(1.0, 1.0, Some("Paris"), Some("France"))),
+    );
+    cache_map.insert(
+        "2.2.2.2".to_string(),
+        Some(test_point(2.0, 2.0, Some("Paris"), Some("United States"))),
+    );
+    cache_map.insert(
+        "3.3.3.3".to_string(),
+        Some(test_point(3.0, 3.0, Some("Lyon"), Some("France"))),
+    );
+    cache_map.insert("4.4.4.4".to_string(), None);
+    let geoip = GeoIpService::from_static(cache_map);
+
+    let stats = geoip.compute_stats().await;
+    assert_eq!(stats.unique_countries, 2);
+    assert_eq!(stats.unique_cities, 3);
+    assert_eq!(stats.total_cached, 4);
+    assert_eq!(stats.coverage_percent, 75.0);
+
+    let empty = GeoIpService::from_static(std::collections::HashMap::new());
+    assert_eq!(empty.compute_stats().await.coverage_percent, 0.0);
+}
+
+#[tokio::test]
+async fn lookup_reliable_matches_lookup_without_swaps() {
+    let geoip = GeoIpService::from_reader(test_city_reader());
+
+    let point = geoip.lookup_reliable("1.2.3.4", 3).await.expect("point");
+    assert_eq!(point.latitude, 10.5);
+    assert!(geoip.lookup_reliable("9.9.9.9", 3).await.is_none());
+    assert_eq!(geoip.total_lookup_count(), 2);
+}
+
+#[tokio::test]
+async fn lookup_reliable_retries_with_the_swapped_in_reader() {
+    let path = std::env::temp_dir().join(format!(
+        "leader-stream-reliable-{}.mmdb",
+        std::process::id()
+    ));
+    let bytes = build_test_mmdb(
+        "GeoIP2-City",
+        &[(
+            "1.2.3.0/24",
+            serde_json::json!({ "location": { "latitude": 40.0, "longitude": 50.0 } }),
+        )],
+    );
+    std::fs::write(&path, bytes).expect("write mmdb");
+    let limit = Arc::new(tokio::sync::Semaphore::new(1));
+    let geoip = GeoIpService::from_reader(test_city_reader()).with_lookup_limit(Arc::clone(&limit));
+
+    // Park the lookup after it has picked up the old reader, then swap.
+    let held = limit.acquire().await.expect("permit");
+    let lookup = tokio::spawn({
+        let geoip = geoip.clone();
+        async move { geoip.lookup_reliable("1.2.3.4", 1).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    geoip.swap_reader(&path).await.expect("swap reader");
+    drop(held);
+
+    let point = tokio::time::timeout(Duration::from_secs(1), lookup)
+        .await
+        .expect("lookup finishes")
+        .expect("lookup task")
+        .expect("point");
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(point.latitude, 40.0);
+    // The stale answer from the old reader did not stay in the cache.
+    assert_eq!(
+        geoip.lookup("1.2.3.4").await.map(|p| p.latitude),
+        Some(40.0)
+    );
+}
+
+#[tokio::test]
+async fn into_arc_shares_cache_with_original_clone() {
+    let geoip = GeoIpService::from_reader(test_city_reader());
+    let shared = geoip.clone().into_arc();
+
+    assert!(shared.lookup("1.2.3.4").await.is_some());
+    assert_eq!(geoip.lookup_all_cached().await.len(), 1);
+}
+
+#[tokio::test]
+async fn coverage_check_reports_database_hits_without_caching() {
+    let geoip = GeoIpService::from_reader(test_city_reader());
+
+    let report = geoip
+        .coverage_check(&["1.2.3.4", "1.2.3.200", "9.9.9.9", "not-an-ip"])
+        .await;
+    assert_eq!(report.total, 4);
+    assert_eq!(report.found, 2);
+    assert_eq!(report.not_found, 2);
+    assert_eq!(report.coverage_pct, 50.0);
+    assert_eq!(geoip.compute_stats().await.total_cached, 0);
+
+    let empty = GeoIpService::new_noop().coverage_check(&[]).await;
+    assert_eq!((empty.total, empty.coverage_pct), (0, 0.0));
+}
+
+#[tokio::test]
+async fn as_json_map_keys_cached_points_by_ip() {
+    let mut cache_map: HashMap<String, Option<GeoPoint>> = (0..crate::geo::MAX_JSON_MAP_ENTRIES
+        + 5)
+        .map(|i| {
+            (
+                format!("10.{}.{}.{}", i >> 16, (i >> 8) & 0xff, i & 0xff),
+                Some(test_point(1.0, 2.0, None, Some("Testland"))),
+            )
+        })
+        .collect();
+    cache_map.insert("0.0.0.1".to_string(), None);
+    let geoip = GeoIpService::from_static(cache_map);
+
+    let map = geoip.as_json_map().await;
+    let object = map.as_object().expect("json object");
+    assert_eq!(object.len(), crate::geo::MAX_JSON_MAP_ENTRIES);
+    assert!(!object.contains_key("0.0.0.1"));
+    assert_eq!(object["10.0.0.0"]["country"], "Testland");
+}
+
+#[tokio::test]
+async fn lookup_all_cached_skips_misses() {
+    let mut cache_map = std::collections::HashMap::new();
+    cache_map.insert(
+        "1.1.1.1".to_string(),
+        Some(test_point(1.0, 1.0, Some("Paris"), Some("France"))),
+    );
+    cache_map.insert("4.4.4.4".to_string(), None);
+    let geoip = GeoIpService::from_static(cache_map);
+
+    let cached = geoip.lookup_all_cached().await;
+    assert_eq!(cached.len(), 1);
+    assert_eq!(cached[0].0, "1.1.1.1");
+    assert_eq!(cached[0].1.city.as_deref(), Some("Paris"));
+}
+
+#[tokio::test]
+async fn evict_nulls_keeps_resolved_entries() {
+    let mut cache_map = std::collections::HashMap::new();
+    cache_map.insert(
+        "1.1.1.1".to_string(),
+        Some(test_point(1.0, 1.0, Some("Paris"), Some("France"))),
+    );
+    cache_map.insert("4.4.4.4".to_string(), None);
+    cache_map.insert("5.5.5.5".to_string(), None);
+    let geoip = GeoIpService::from_static(cache_map);
+
+    assert_eq!(geoip.evict_nulls().await, 2);
+    assert_eq!(geoip.compute_stats().await.total_cached, 1);
+    assert_eq!(geoip.evict_nulls().await, 0);
+}
+
+#[tokio::test]
+async fn drain_cache_returns_entries_and_empties_the_cache() {
+    let geoip = GeoIpService::from_reader(test_city_reader());
+    assert!(geoip.lookup("1.2.3.4").await.is_some());
+    assert!(geoip.lookup("9.9.9.9").await.is_none());
+
+    let drained = geoip.drain_cache().await;
+    assert_eq!(drained.len(), 2);
+    assert_eq!(
+        drained["1.2.3.4"].as_ref().and_then(|p| p.city.as_deref()),
+        Some("Test City")
+    );
+    assert_eq!(drained["9.9.9.9"], None);
+    assert_eq!(geoip.compute_stats().await.total_cached, 0);
+    assert!(geoip.drain_cache().await.is_empty());
+}
+
+#[tokio::test]
+async fn dump_csv_quotes_fields_with_commas() {
+    let mut cache_map = std::collections::HashMap::new();
+    cache_map.insert(
+        "2.2.2.2".to_string(),
+        Some(test_point(
+            2.5,
+            -3.0,
+            Some("Washington, D.C."),
+            Some("United States"),
+        )),
+    );
+    cache_map.insert(
+        "1.1.1.1".to_string(),
+        Some(test_point(1.0, 1.0, None, Some("France"))),
+    );
+    cache_map.insert("4.4.4.4".to_string(), None);
+    let geoip = GeoIpService::from_static(cache_map);
+
+    let mut out = Vec::new();
+    assert_eq!(geoip.dump_csv(&mut out).await.expect("dump csv"), 2);
+    assert_eq!(
+        String::from_utf8(out).expect("utf8"),
+        "ip,latitude,longitude,city,country\n\
+         1.1.1.1,1,1,,France\n\
+         2.2.2.2,2.5,-3,\"Washington, D.C.\",United States\n"
+    );
+}
+
+#[tokio::test]
+async fn load_csv_round_trips_dump_csv() {
+    let mut cache_map = std::collections::HashMap::new();
+    cache_map.insert(
+        "2.2.2.2".to_string(),
+        Some(test_point(
+            2.5,
+            -3.0,
+            Some("Washington, D.C."),
+            Some("United States"),
+        )),
+    );
+    cache_map.insert(
+        "1.1.1.1".to_string(),
+        Some(test_point(1.0, 1.0, Some("Say \"Hi\""), None)),
+    );
+    let source = GeoIpService::from_static(cache_map);
+    let mut dump = Vec::new();
+    source.dump_csv(&mut dump).await.expect("dump csv");
+
+    let target = GeoIpService::new_noop();
+    let mut input = dump.clone();
+    input.extend_from_slice(b"3.3.3.3,north,0,,\nnot-an-ip,0,0,,\n");
+    assert_eq!(
+        target
+            .load_csv(std::io::Cursor::new(input))
+            .await
+            .expect("load csv"),
+        2
+    );
+
+    let mut reloaded = Vec::new();
+    target.dump_csv(&mut reloaded).await.expect("dump csv");
+    assert_eq!(reloaded, dump);
+}
+
+#[test]
+fn dedup_by_location_collapses_consecutive_near_duplicates() {
+    let mut points = vec![
+        test_point(10.0, 20.0, Some("A"), None),
+        test_point(10.00001, 20.00001, Some("A again"), None),
+        test_point(11.0, 21.0, Some("B"), None),
+    ];
+    GeoPoint::dedup_by_location(&mut points, 0.001);
+    assert_eq!(points.len(), 2);
+    assert_eq!(points[0].city.as_deref(), Some("A"));
+    assert_eq!(points[1].city.as_deref(), Some("B"));
+}
+
+#[test]
+fn database_validation_rejects_html_pages() {
+    let bytes = build_test_mmdb("GeoIP2-City", &[]);
+    assert!(ensure_mmdb_bytes(&bytes).is_ok());
+
+    let err = ensure_mmdb_bytes(b"\n<!DOCTYPE html><html>Not Found</html>").unwrap_err();
+    assert!(err.to_string().contains("got HTML?"));
+    assert!(ensure_mmdb_bytes(b"not a database").is_err());
+}
+
+#[cfg(feature = "h3")]
+#[test]
+fn h3_cells_for_nearby_cities_are_shared_or_adjacent() {
+    let frankfurt = test_point(50.1109, 8.6821, Some("Frankfurt"), Some("Germany"));
+    let offenbach = test_point(50.0956, 8.7761, Some("Offenbach"), Some("Germany"));
+    let a = frankfurt.to_h3_cell(4).expect("frankfurt cell");
+    let b = offenbach.to_h3_cell(4).expect("offenbach cell");
+    assert!(a == b || a.is_neighbor_with(b).expect("same resolution"));
+
+    assert!(frankfurt.to_h3_cell(16).is_none());
+    let invalid = GeoPoint {
+        latitude: 120.0,
+        ..frankfurt
+    };
+    assert!(invalid.to_h3_cell(4).is_none());
+}
+
+#[cfg(feature = "chrono-tz")]
+#[test]
+fn to_local_datetime_follows_dst_transitions() {
+    use chrono::{TimeZone, Timelike, Utc};
+
+    let new_york = GeoPoint {
+        timezone: Some("America/New_York".to_string()),
+        ..test_point(40.7128, -74.006, Some("New York"), Some("United States"))
+    };
+    let local_hour = |utc| {
+        let local = new_york.to_local_datetime(utc).expect("local time");
+        (local.hour(), local.offset().to_string())
+    };
+
+    // Spring forward: 02:00 EST jumps to 03:00 EDT on 2024-03-10.
+    let before = Utc.with_ymd_and_hms(2024, 3, 10, 6, 59, 0).unwrap();
+    let after = Utc.with_ymd_and_hms(2024, 3, 10, 7, 0, 0).unwrap();
+    assert_eq!(local_hour(before), (1, "EST".to_string()));
+    assert_eq!(local_hour(after), (3, "EDT".to_string()));
+    // Fall back: 02:00 EDT returns to 01:00 EST on 2024-11-03.
+    let before = Utc.with_ymd_and_hms(2024, 11, 3, 5, 59, 0).unwrap();
+    let after = Utc.with_ymd_and_hms(2024, 11, 3, 6, 0, 0).unwrap();
+    assert_eq!(local_hour(before), (1, "EDT".to_string()));
+    assert_eq!(local_hour(after), (1, "EST".to_string()));
+
+    assert!(test_point(0.0, 0.0, None, None)
+        .to_local_datetime(after)
+        .is_none());
+    let unknown = GeoPoint {
+        timezone: Some("Mars/Olympus_Mons".to_string()),
+        ..test_point(0.0, 0.0, None, None)
+    };
+    assert!(unknown.to_local_datetime(after).is_none());
+}
+
+#[cfg(feature = "geohash")]
+#[tokio::test]
+async fn lookup_with_geohash_shares_prefix_for_nearby_ips() {
+    let geoip = GeoIpService::new_noop().with_overrides(HashMap::from([
+        (
+            "10.0.0.1".to_string(),
+            test_point(52.52, 13.405, Some("Berlin"), Some("Germany")),
+        ),
+        (
+            "10.0.0.2".to_string(),
+            test_point(52.3906, 13.0645, Some("Potsdam"), Some("Germany")),
+        ),
+        (
+            "10.0.0.3".to_string(),
+            test_point(48.8566, 2.3522, Some("Paris"), Some("France")),
+        ),
+    ]));
+
+    let (point, berlin) = geoip
+        .lookup_with_geohash("10.0.0.1", 7)
+        .await
+        .expect("berlin");
+    assert_eq!(point.city(), Some("Berlin"));
+    assert_eq!(berlin.len(), 7);
+    let (_, potsdam) = geoip
+        .lookup_with_geohash("10.0.0.2", 7)
+        .await
+        .expect("potsdam");
+    let (_, paris) = geoip
+        .lookup_with_geohash("10.0.0.3", 7)
+        .await
+        .expect("paris");
+    assert_eq!(berlin[..3], potsdam[..3]);
+    assert_ne!(berlin[..2], paris[..2]);
+
+    assert!(geoip.lookup_with_geohash("10.0.0.1", 0).await.is_none());
+    assert!(geoip.lookup_with_geohash("9.9.9.9", 7).await.is_none());
+}
+
+#[cfg(feature = "mgrs")]
+#[test]
+fn mgrs_round_trips_within_100_meters() {
+    let berlin = test_point(52.52, 13.405, Some("Berlin"), Some("Germany"));
+    let mgrs = berlin.to_mgrs().expect("mgrs string");
+    let parsed = geoconvert::Mgrs::parse_str(&mgrs)
+        .expect("parse mgrs")
+        .to_latlon();
+    let north_m = (parsed.latitude() - berlin.latitude) * 111_320.0;
+    let east_m =
+        (parsed.longitude() - berlin.longitude) * 111_320.0 * berlin.latitude.to_radians().cos();
+    assert!((north_m * north_m + east_m * east_m).sqrt() < 100.0);
+
+    let pole = GeoPoint {
+        latitude: 89.0,
+        ..berlin
+    };
+    assert!(pole.to_mgrs().is_none());
+}
+
+#[cfg(feature = "openlocationcode")]
+#[test]
+fn plus_code_round_trips_within_olc_precision() {
+    let london = test_point(51.5074, -0.1278, Some("London"), Some("United Kingdom"));
+    let code = london.to_plus_code();
+    assert_eq!(code, "9C3XGV4C+XV");
+
+    let area = open_location_code::decode(&code).expect("decode plus code");
+    assert!((area.south..=area.north).contains(&london.latitude));
+    assert!((area.west..=area.east).contains(&london.longitude));
+    assert!((area.center.y() - london.latitude).abs() < 0.000125);
+    assert!((area.center.x() - london.longitude).abs() < 0.000125);
+}
+
+#[cfg(feature = "sink")]
+#[tokio::test]
+async fn annotate_sink_pairs_payloads_with_lookups() {
+    use futures_util::{SinkExt, StreamExt};
+
+    let geoip = GeoIpService::from_reader(test_city_reader());
+    let (mut sink, stream) = geoip.annotate_sink::<u32>(4);
+    sink.send(("1.2.3.4".to_string(), 1)).await.expect("send");
+    sink.send(("9.9.9.9".to_string(), 2)).await.expect("send");
+    drop(sink);
+
+    let annotated: Vec<_> = stream.collect().await;
+    assert_eq!(annotated.len(), 2);
+    assert_eq!(
+        annotated[0].0.as_ref().and_then(|p| p.city.as_deref()),
+        Some("Test City")
+    );
+    assert_eq!(annotated[0].1, 1);
+    assert!(annotated[1].0.is_none());
+    assert_eq!(annotated[1].1, 2);
+}
+
+#[cfg(feature = "ipc")]
+#[tokio::test]
+async fn cache_socket_forwards_lookups_to_server() {
+    let path = std::env::temp_dir().join(format!("leader-stream-ipc-{}.sock", std::process::id()));
+    let server = GeoIpService::from_reader(test_city_reader());
+    let task = server.bind_cache_socket(&path).expect("bind cache socket");
+    let client = GeoIpService::new_noop();
+    client
+        .connect_cache_socket(&path)
+        .await
+        .expect("connect cache socket");
+
+    let point = client.lookup("1.2.3.4").await.expect("forwarded lookup");
+    assert_eq!(point.city.as_deref(), Some("Test City"));
+    assert!(client.lookup("9.9.9.9").await.is_none());
+    assert_eq!(server.total_lookup_count(), 2);
+    assert_eq!(client.compute_stats().await.total_cached, 2);
+
+    task.abort();
+    let _ = task.await;
+    // The server is gone; the client falls back to its own (empty) database.
+    assert!(client.lookup("1.2.3.5").await.is_none());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[cfg(feature = "governor")]
+#[tokio::test]
+async fn rate_limited_lookup_skips_when_out_of_tokens() {
+    let limiter = governor::RateLimiter::direct(governor::Quota::per_hour(
+        std::num::NonZeroU32::new(1).expect("non-zero"),
+    ));
+    let geoip = GeoIpService::from_reader(test_city_reader());
+
+    assert!(geoip
+        .rate_limited_lookup("1.2.3.4", &limiter)
+        .await
+        .is_some());
+    assert!(geoip
+        .rate_limited_lookup("1.2.3.5", &limiter)
+        .await
+        .is_none());
+    assert!(geoip
+        .rate_limited_lookup("1.2.3.4", &limiter)
+        .await
+        .is_some());
+}
+
+#[cfg(feature = "tokio-io")]
+#[tokio::test]
+async fn lookup_and_record_appends_json_lines() {
+    let geoip = GeoIpService::from_reader(test_city_reader());
+    let mut log = Vec::new();
+
+    assert!(geoip.lookup_and_record("1.2.3.4", &mut log).await.is_some());
+    assert!(geoip.lookup_and_record("9.9.9.9", &mut log).await.is_none());
+
+    let text = String::from_utf8(log).expect("utf8");
+    let records: Vec<serde_json::Value> = text
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("json line"))
+        .collect();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["ip"], "1.2.3.4");
+    assert_eq!(records[0]["lat"], 10.5);
+    assert_eq!(records[0]["lon"], -20.25);
+    assert!(records[0]["ts"].as_str().expect("ts").ends_with('Z'));
+    assert!(records[1]["lat"].is_null());
+}
+
+#[cfg(feature = "tokio-io")]
+#[tokio::test]
+async fn audit_log_records_lookups_and_rotates() {
+    let path = std::env::temp_dir().join(format!("leader-stream-audit-{}.log", std::process::id()));
+    let mut rotated = path.clone().into_os_string();
+    rotated.push(".1");
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&rotated);
+
+    let geoip = GeoIpService::from_reader(test_city_reader())
+        .with_audit_log(path.clone())
+        .await
+        .expect("open audit log");
+    geoip.lookup("1.2.3.4").await;
+    geoip.lookup("1.2.3.4").await;
+    geoip.lookup("9.9.9.9").await;
+
+    let text = std::fs::read_to_string(&path).expect("read audit log");
+    let records: Vec<serde_json::Value> = text
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("json line"))
+        .collect();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0]["ip"], "1.2.3.4");
+    assert_eq!(records[0]["cache_hit"], false);
+    assert_eq!(records[0]["found"], true);
+    assert_eq!(records[1]["cache_hit"], true);
+    assert_eq!(records[2]["found"], false);
+    assert!(records[0]["ts"].as_str().expect("ts").ends_with('Z'));
+
+    let geoip = GeoIpService::from_reader(test_city_reader())
+        .with_max_audit_log_bytes(1)
+        .with_audit_log(path.clone())
+        .await
+        .expect("reopen audit log");
+    geoip.lookup("1.2.3.4").await;
+    let rotated_text = std::fs::read_to_string(&rotated).expect("read rotated log");
+    assert_eq!(rotated_text.lines().count(), 4);
+    assert_eq!(std::fs::read_to_string(&path).expect("read fresh log"), "");
+
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&rotated);
+}
+
+#[cfg(feature = "tokio-io")]
+#[tokio::test]
+async fn lookup_all_from_log_file_streams_client_ips() {
+    use futures_util::StreamExt;
+
+    let path =
+        std::env::temp_dir().join(format!("leader-stream-access-{}.log", std::process::id()));
+    std::fs::write(
+        &path,
+        concat!(
+            "1.2.3.4 - - [10/Oct/2024:13:55:36 +0000] \"GET / HTTP/1.1\" 200 2326\n",
+            "\n",
+            "9.9.9.9 - frank [10/Oct/2024:13:55:37 +0000] \"GET /a HTTP/1.1\" 404 0\n",
+            "1.2.3.4 - - [10/Oct/2024:13:55:38 +0000] \"GET /b HTTP/1.1\" 200 12\n",
+        ),
+    )
+    .expect("write log");
+    let geoip = GeoIpService::from_reader(test_city_reader());
+
+    let results: Vec<_> = geoip
+        .lookup_all_from_log_file(&path)
+        .await
+        .expect("open log")
+        .collect()
+        .await;
+    let summary: Vec<_> = results
+        .iter()
+        .map(|(ip, point)| (ip.as_str(), point.as_ref().map(|p| p.latitude)))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            ("1.2.3.4", Some(10.5)),
+            ("9.9.9.9", None),
+            ("1.2.3.4", Some(10.5))
+        ]
+    );
+    assert_eq!(geoip.total_cache_hit_count(), 1);
+    assert!(geoip
+        .lookup_all_from_log_file(&path.with_extension("missing"))
+        .await
+        .is_err());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[cfg(feature = "nominatim")]
+#[tokio::test]
+async fn fetch_nominatim_address_reads_display_name_and_spaces_requests() {
+    use crate::geo::fetch_nominatim_address;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+    let app = axum::Router::new().route(
+        "/reverse",
+        axum::routing::get(
+            |axum::extract::Query(query): axum::extract::Query<HashMap<String, String>>| async move {
+                if query.get("lat").map(String::as_str) == Some("0") {
+                    axum::Json(serde_json::json!({ "error": "Unable to geocode" }))
+                } else {
+                    axum::Json(serde_json::json!({
+                        "display_name": "Pariser Platz, Mitte, Berlin, Deutschland",
+                    }))
+                }
+            },
+        ),
+    );
+    tokio::spawn(async move {
+        let _ = axum::serve(listener, app).await;
+    });
+
+    let client = reqwest::Client::new();
+    let interval = Duration::from_millis(100);
+    let started = std::time::Instant::now();
+    let found = fetch_nominatim_address(
+        &client,
+        &format!("http://{addr}/reverse?lat=52.516&lon=13.378&format=json"),
+        interval,
+    )
+    .await
+    .expect("address");
+    assert_eq!(
+        found.as_deref(),
+        Some("Pariser Platz, Mitte, Berlin, Deutschland")
+    );
+    let missing = fetch_nominatim_address(
+        &client,
+        &format!("http://{addr}/reverse?lat=0&lon=0&format=json"),
+        interval,
+    )
+    .await
+    .expect("response");
+    assert!(missing.is_none());
+    assert!(started.elapsed() >= interval);
+}
+
+#[cfg(feature = "prometheus")]
+#[tokio::test]
+async fn export_prometheus_text_parses_as_exposition_format() {
+    let geoip = GeoIpService::from_reader(test_city_reader());
+    geoip.lookup("1.2.3.4").await;
+    geoip.lookup("1.2.3.4").await;
+    geoip.lookup("9.9.9.9").await;
+
+    let text = geoip.export_prometheus_text().await;
+    let scrape = prometheus_parse::Scrape::parse(text.lines().map(|line| Ok(line.to_string())))
+        .expect("valid exposition format");
+    let value = |name: &str| {
+        scrape
+            .samples
+            .iter()
+            .find(|sample| sample.metric == name)
+            .map(|sample| sample.value.clone())
+    };
+    assert_eq!(
+        value("geoip_cache_entries"),
+        Some(prometheus_parse::Value::Gauge(2.0))
+    );
+    assert_eq!(
+        value("geoip_cache_hits_total"),
+        Some(prometheus_parse::Value::Counter(1.0))
+    );
+    assert_eq!(
+        value("geoip_cache_misses_total"),
+        Some(prometheus_parse::Value::Counter(2.0))
+    );
+    match value("geoip_database_loaded_timestamp_seconds") {
+        Some(prometheus_parse::Value::Gauge(loaded)) => assert!(loaded > 1.7e9),
+        other => panic!("unexpected load timestamp {other:?}"),
+    }
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn lookup_blocking_runs_without_tokio() {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(2)
+        .build()
+        .expect("rayon pool");
+    let geoip = GeoIpService::from_reader(test_city_reader());
+
+    let point = geoip.lookup_blocking("1.2.3.4", &pool).expect("lookup");
+    assert_eq!(point.city.as_deref(), Some("Test City"));
+    assert!(geoip.lookup_blocking("9.9.9.9", &pool).is_none());
+    assert!(geoip.lookup_blocking("1.2.3.4", &pool).is_some());
+}
+
+fn arbitrary_point() -> impl proptest::strategy::Strategy<Value = GeoPoint> {
+    use proptest::prelude::*;
+    (
+        -90.0..=90.0f64,
+        -180.0..=180.0f64,
+        proptest::option::of("[A-Za-z ]{1,16}"),
+        proptest::option::of("[A-Za-z ]{1,16}"),
+    )
+        .prop_map(|(latitude, longitude, city, country)| {
+            test_point(latitude, longitude, city.as_deref(), country.as_deref())
+        })
+}
+
+proptest::proptest! {
+    #[test]
+    fn anonymized_points_stay_in_range_and_near_the_original(point in arbitrary_point()) {
+        let anonymized = point.anonymized();
+        proptest::prop_assert!((-90.0..=90.0).contains(&anonymized.latitude));
+        proptest::prop_assert!((-180.0..=180.0).contains(&anonymized.longitude));
+        proptest::prop_assert!((anonymized.latitude - point.latitude).abs() <= 0.05 + 1e-9);
+        proptest::prop_assert!((anonymized.longitude - point.longitude).abs() <= 0.05 + 1e-9);
+        proptest::prop_assert!(anonymized.city.is_none());
+        proptest::prop_assert_eq!(anonymized.country.as_deref(), point.country.as_deref());
+    }
+
+    #[test]
+    fn anonymization_is_idempotent(point in arbitrary_point()) {
+        let once = point.anonymized();
+        let twice = once.anonymized();
+        proptest::prop_assert_eq!(once.latitude, twice.latitude);
+        proptest::prop_assert_eq!(once.longitude, twice.longitude);
+    }
+
+    #[test]
+    fn points_round_trip_through_json(point in arbitrary_point()) {
+        let json = serde_json::to_string(&point).expect("serialize point");
+        let parsed: GeoPoint = serde_json::from_str(&json).expect("deserialize point");
+        // serde_json's default float parser may be off by one ulp.
+        proptest::prop_assert!((parsed.latitude - point.latitude).abs() <= 1e-12);
+        proptest::prop_assert!((parsed.longitude - point.longitude).abs() <= 1e-12);
+        proptest::prop_assert_eq!(
+            GeoPoint { latitude: point.latitude, longitude: point.longitude, ..parsed },
+            point
+        );
+    }
+
+    #[test]
+    fn distances_are_non_negative_and_symmetric(a in arbitrary_point(), b in arbitrary_point()) {
+        let forward = a.distance_km(&b);
+        let backward = b.distance_km(&a);
+        proptest::prop_assert!(forward >= 0.0);
+        proptest::prop_assert!((forward - backward).abs() <= 1e-6);
+        proptest::prop_assert!(a.distance_km(&a).abs() <= 1e-6);
+    }
+
+    #[test]
+    fn weighted_centroid_stays_inside_the_bounding_box(
+        base_latitude in -60.0..=60.0f64,
+        base_longitude in -170.0..=170.0f64,
+        offsets in proptest::collection::vec((0.0..=5.0f64, 0.0..=5.0f64, 0.1..=10.0f64), 1..8),
+    ) {
+        let points: Vec<(GeoPoint, f64)> = offsets
+            .iter()
+            .map(|(lat, lon, weight)| {
+                (test_point(base_latitude + lat, base_longitude + lon, None, None), *weight)
+            })
+            .collect();
+        let centroid = GeoPoint::weighted_centroid(&points).expect("centroid");
+        let (min_lat, max_lat) = points.iter().fold((f64::MAX, f64::MIN), |(lo, hi), (p, _)| {
+            (lo.min(p.latitude), hi.max(p.latitude))
+        });
+        let (min_lon, max_lon) = points.iter().fold((f64::MAX, f64::MIN), |(lo, hi), (p, _)| {
+            (lo.min(p.longitude), hi.max(p.longitude))
+        });
+        // Averaging on the sphere bulges slightly poleward of a lat/lon box.
+        let slack = 0.1;
+        proptest::prop_assert!(centroid.latitude >= min_lat - slack);
+        proptest::prop_assert!(centroid.latitude <= max_lat + slack);
+        proptest::prop_assert!(centroid.longitude >= min_lon - slack);
+        proptest::prop_assert!(centroid.longitude <= max_lon + slack);
+    }
+
+    #[test]
+    fn weighted_centroid_is_stable(
+        points in proptest::collection::vec((arbitrary_point(), 0.1..=10.0f64), 1..8),
+        scale in 0.5..=20.0f64,
+    ) {
+        // A single point is its own centroid, and scaling every weight by the
+        // same factor must not move the result.
+        let (single, weight) = &points[0];
+        let own = GeoPoint::weighted_centroid(&[(single.clone(), *weight)]).expect("centroid");
+        proptest::prop_assert!(own.distance_km(single) < 1e-3);
+
+        let scaled: Vec<(GeoPoint, f64)> = points
+            .iter()
+            .map(|(point, weight)| (point.clone(), weight * scale))
+            .collect();
+        match (GeoPoint::weighted_centroid(&points), GeoPoint::weighted_centroid(&scaled)) {
+            (Some(original), Some(rescaled)) => {
+                proptest::prop_assert!(original.distance_km(&rescaled) < 1e-3);
+            }
+            (original, rescaled) => {
+                proptest::prop_assert_eq!(original.is_none(), rescaled.is_none());
+            }
+        }
+    }
+}
+
+#[cfg(feature = "h3")]
+proptest::proptest! {
+    #[test]
+    fn h3_cells_exist_for_every_valid_point(point in arbitrary_point(), resolution in 0u8..=15) {
+        let cell = point.to_h3_cell(resolution);
+        proptest::prop_assert!(cell.is_some());
+        proptest::prop_assert_eq!(u8::from(cell.unwrap().resolution()), resolution);
+    }
+}
+
+#[cfg(feature = "mgrs")]
+proptest::proptest! {
+    #[test]
+    fn mgrs_round_trips_for_non_polar_points(
+        latitude in -79.9..=83.9f64,
+        longitude in -179.9..=179.9f64,
+    ) {
+        let point = test_point(latitude, longitude, None, None);
+        let mgrs = point.to_mgrs().expect("mgrs string");
+        let parsed = geoconvert::Mgrs::parse_str(&mgrs).expect("parse mgrs").to_latlon();
+        let north_m = (parsed.latitude() - latitude) * 111_320.0;
+        let mut east_deg = parsed.longitude() - longitude;
+        if east_deg > 180.0 {
+            east_deg -= 360.0;
+        } else if east_deg < -180.0 {
+            east_deg += 360.0;
+        }
+        let east_m = east_deg * 111_320.0 * latitude.to_radians().cos();
+        proptest::prop_assert!((north_m * north_m + east_m * east_m).sqrt() < 100.0);
+    }
+}
+
+#[tokio::test]
+async fn timezone_fallback_csv_fills_missing_time_zones() {
+    let path = std::env::temp_dir().join(format!("leader-stream-tz-{}.csv", std::process::id()));
+    std::fs::write(
+        &path,
+        "city,timezone\n# exported from GeoNames\n\nTEST CITY,Atlantic/Azores\nWashington, D.C.,America/New_York\n",
+    )
+    .expect("write csv");
+    let timezones = crate::geo::load_timezone_fallback_csv(&path).expect("load csv");
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(timezones.len(), 2);
+    assert_eq!(
+        timezones.get("Washington, D.C.").map(String::as_str),
+        Some("America/New_York")
+    );
+
+    let bytes = build_test_mmdb(
+        "GeoIP2-City",
+        &[
+            (
+                "1.2.3.0/24",
+                serde_json::json!({
+                    "city": { "names": { "en": "Test City" } },
+                    "location": { "latitude": 10.5, "longitude": -20.25 },
+                }),
+            ),
+            (
+                "5.6.7.0/24",
+                serde_json::json!({
+                    "city": { "names": { "en": "Test City" } },
+                    "location": {
+                        "latitude": 10.5,
+                        "longitude": -20.25,
+                        "time_zone": "Europe/Lisbon",
+                    },
+                }),
+            ),
+        ],
+    );
+    let geoip = GeoIpService::from_reader(maxminddb::Reader::from_source(bytes).expect("mmdb"))
+        .with_timezone_fallback(timezones);
+
+    let fallback = geoip.lookup("1.2.3.4").await.expect("fallback point");
+    assert_eq!(fallback.timezone.as_deref(), Some("Atlantic/Azores"));
+    let native = geoip.lookup("5.6.7.8").await.expect("native point");
+    assert_eq!(native.timezone.as_deref(), Some("Europe/Lisbon"));
+}
+
+#[tokio::test]
+async fn generate_heatmap_counts_cached_points_per_cell() {
+    let geoip = GeoIpService::new_noop().with_overrides(HashMap::from([
+        (
+            "10.0.0.1".to_string(),
+            test_point(51.5, -0.1, Some("London"), Some("United Kingdom")),
+        ),
+        (
+            "10.0.0.2".to_string(),
+            test_point(48.9, 2.4, Some("Paris"), Some("France")),
+        ),
+        (
+            "10.0.0.3".to_string(),
+            test_point(-33.9, 151.2, Some("Sydney"), Some("Australia")),
+        ),
+        (
+            "10.0.0.4".to_string(),
+            test_point(90.0, 180.0, Some("North Pole"), Some("Nowhere")),
+        ),
+    ]));
+    geoip.lookup("192.0.2.1").await;
+
+    // 90-degree columns from the antimeridian; rows split at the equator.
+    // The cached miss for 192.0.2.1 is not counted.
+    let grid = geoip.generate_heatmap(4, 2).await;
+    assert_eq!(grid, vec![vec![0, 1, 1, 1], vec![0, 0, 0, 1]]);
+    assert!(geoip.generate_heatmap(0, 2).await.is_empty());
+}
+
+#[cfg(feature = "grpc")]
+#[tokio::test]
+async fn health_service_reports_serving_status() {
+    use tonic::server::NamedService;
+    use tonic_health::pb::health_check_response::ServingStatus;
+    use tonic_health::pb::health_server::Health;
+    use tonic_health::pb::HealthCheckRequest;
+
+    fn service_name<S: NamedService>(_: &S) -> &'static str {
+        S::NAME
+    }
+    let server = GeoIpService::new_noop().into_health_service();
+    assert_eq!(service_name(&server), "grpc.health.v1.Health");
+    let check = |geoip: GeoIpService, service: &str| {
+        let request = tonic::Request::new(HealthCheckRequest {
+            service: service.to_string(),
+        });
+        async move {
+            let health = crate::geo::GeoIpHealthService::new(geoip);
+            health.check(request).await
+        }
+    };
+
+    let serving = check(GeoIpService::from_reader(test_city_reader()), "")
+        .await
+        .expect("check");
+    assert_eq!(serving.get_ref().status, ServingStatus::Serving as i32);
+    let not_serving = check(GeoIpService::new_noop(), "").await.expect("check");
+    assert_eq!(
+        not_serving.get_ref().status,
+        ServingStatus::NotServing as i32
+    );
+    let unknown = check(GeoIpService::from_reader(test_city_reader()), "other")
+        .await
+        .expect_err("unknown service");
+    assert_eq!(unknown.code(), tonic::Code::NotFound);
+}
+
+#[cfg(feature = "opentelemetry")]
+#[tokio::test]
+async fn lookup_with_otel_exports_span_with_geo_attributes() {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry::Value;
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+
+    let exporter = InMemorySpanExporter::default();
+    let provider = SdkTracerProvider::builder()
+        .with_simple_exporter(exporter.clone())
+        .build();
+    let tracer = provider.tracer("leader-stream-test");
+    let geoip = GeoIpService::from_reader(test_city_reader());
+
+    assert!(geoip.lookup_with_otel("1.2.3.4", &tracer).await.is_some());
+    assert!(geoip.lookup_with_otel("9.9.9.9", &tracer).await.is_none());
+
+    let spans = exporter.get_finished_spans().expect("finished spans");
+    assert_eq!(spans.len(), 2);
+    let attribute = |index: usize, key: &str| {
+        spans[index]
+            .attributes
+            .iter()
+            .find(|attribute| attribute.key.as_str() == key)
+            .map(|attribute| attribute.value.clone())
+    };
+    assert_eq!(spans[0].name, "geo_lookup");
+    assert_eq!(
+        attribute(0, "net.peer.ip"),
+        Some(Value::from("1.2.3.4".to_string()))
+    );
+    assert_eq!(
+        attribute(0, "geo.country.iso_code"),
+        Some(Value::from("TL".to_string()))
+    );
+    assert_eq!(
+        attribute(0, "geo.city.name"),
+        Some(Value::from("Test City".to_string()))
+    );
+    assert_eq!(
+        attribute(1, "net.peer.ip"),
+        Some(Value::from("9.9.9.9".to_string()))
+    );
+    assert!(attribute(1, "geo.city.name").is_none());
+}
+
+#[tokio::test]
+async fn select_leader_picks_nearest_candidate_with_stable_ties() {
+    let geoip = GeoIpService::new_noop().with_overrides(HashMap::from([
+        (
+            "10.0.0.1".to_string(),
+            test_point(51.5, -0.1, Some("London"), Some("United Kingdom")),
+        ),
+        (
+            "10.0.0.9".to_string(),
+            test_point(48.9, 2.4, Some("Paris"), Some("France")),
+        ),
+        (
+            "10.0.0.10".to_string(),
+            test_point(48.9, 2.4, Some("Paris"), Some("France")),
+        ),
+        (
+            "10.0.0.3".to_string(),
+            test_point(-33.9, 151.2, Some("Sydney"), Some("Australia")),
+        ),
+    ]));
+
+    let candidates = ["10.0.0.3", "10.0.0.9", "192.0.2.1", "10.0.0.10"];
+    assert_eq!(
+        geoip.select_leader(&candidates, "10.0.0.1").await,
+        Some("10.0.0.10")
+    );
+    assert_eq!(
+        geoip.select_leader(&["10.0.0.3"], "10.0.0.1").await,
+        Some("10.0.0.3")
+    );
+    assert_eq!(geoip.select_leader(&[], "10.0.0.1").await, None);
+    assert_eq!(geoip.select_leader(&["192.0.2.1"], "10.0.0.1").await, None);
+    assert_eq!(geoip.select_leader(&candidates, "192.0.2.2").await, None);
+}
+
+#[tokio::test]
+async fn lookup_limit_holds_permit_until_lookup_completes() {
+    let limit = Arc::new(tokio::sync::Semaphore::new(1));
+    let geoip = GeoIpService::from_reader(test_city_reader()).with_lookup_limit(Arc::clone(&limit));
+
+    let held = limit.acquire().await.expect("permit");
+    let lookup = tokio::spawn({
+        let geoip = geoip.clone();
+        async move { geoip.lookup("1.2.3.4").await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!lookup.is_finished());
+    assert!(geoip.lookup_all_cached().await.is_empty());
+
+    drop(held);
+    let point = tokio::time::timeout(Duration::from_secs(1), lookup)
+        .await
+        .expect("lookup finishes once the permit is free")
+        .expect("lookup task");
+    assert_eq!(point.map(|p| p.latitude), Some(10.5));
+    // The permit is back once the lookup and its cache write are done.
+    assert_eq!(limit.available_permits(), 1);
+}
+
+#[tokio::test]
+async fn lookup_timeout_resolves_to_none_without_caching() {
+    let limit = Arc::new(tokio::sync::Semaphore::new(1));
+    let geoip = GeoIpService::from_reader(test_city_reader())
+        .with_lookup_limit(Arc::clone(&limit))
+        .with_lookup_timeout(Duration::from_millis(30));
+
+    let held = limit.acquire().await.expect("permit");
+    assert!(geoip.lookup("1.2.3.4").await.is_none());
+    // Not even a miss is cached, so the next lookup goes to the database.
+    assert_eq!(geoip.compute_stats().await.total_cached, 0);
+
+    drop(held);
+    assert_eq!(
+        geoip.lookup("1.2.3.4").await.map(|p| p.latitude),
+        Some(10.5)
+    );
+    assert_eq!(geoip.compute_stats().await.total_cached, 1);
+}